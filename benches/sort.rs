@@ -0,0 +1,74 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use image::{Rgba, RgbaImage};
+use sorting_pixels_experiment::{rgb_to_hsv, sort_pixels, ContrastType, PixelSorter, SortBy, SortDirection};
+
+const SIZE: u32 = 2048;
+
+type KeyFn = fn(&Rgba<u8>) -> f32;
+
+/// A noisy gradient, so spans are long and keys repeat like they do in photos.
+fn test_image() -> RgbaImage {
+    let mut state: u32 = 0x9E37_79B9;
+    RgbaImage::from_fn(SIZE, SIZE, |x, y| {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        Rgba([(state >> 24) as u8, (y / 8) as u8, (x / 8) as u8, 255])
+    })
+}
+
+/// The original per-comparison sort, converting both pixels to HSV on every comparison.
+fn sort_rows_with_comparator(pixels: &[Rgba<u8>], contrast_map: &[bool], key: KeyFn) -> Vec<Rgba<u8>> {
+    let width = SIZE as usize;
+    let mut pixels_vec = pixels.to_vec();
+    for (row, row_map) in pixels_vec.chunks_mut(width).zip(contrast_map.chunks(width)) {
+        let mut i = 0;
+        while i < row.len() {
+            if row_map[i] {
+                let mut j = i+1;
+                while j < row.len() && row_map[j] {
+                    j += 1;
+                }
+                row[i..j].sort_unstable_by(|a, b| key(a).partial_cmp(&key(b)).unwrap());
+                i = j;
+            } else {
+                i += 1;
+            }
+        }
+    }
+    pixels_vec
+}
+
+fn bench_sort_keys(c: &mut Criterion) {
+    let img = test_image();
+    let mut group = c.benchmark_group("sort 2048x2048 right");
+    group.sample_size(10);
+
+    let pixels: Vec<Rgba<u8>> = img.pixels().copied().collect();
+    let keys: [(SortBy, KeyFn); 3] = [
+        (SortBy::Red, |pixel| pixel[0] as f32),
+        (SortBy::Hue, |pixel| rgb_to_hsv(pixel).0),
+        (SortBy::Value, |pixel| rgb_to_hsv(pixel).2),
+    ];
+    for (sort_by, key) in keys {
+        let sorter = PixelSorter::new()
+            .sort_direction(SortDirection::Right)
+            .sort_by(sort_by.clone())
+            .contrast_type(ContrastType::Blue)
+            .contrast_bounds(0.1, 1.0)
+            .threads(1);
+        let contrast_map = sorter.contrast_map(&img).unwrap();
+        let contrast_values = sorter.contrast_values(&img);
+
+        group.bench_function(BenchmarkId::new("comparator", format!("{:?}", sort_by)), |b| b.iter(|| sort_rows_with_comparator(&pixels, &contrast_map, key)));
+        group.bench_function(BenchmarkId::new("precomputed keys", format!("{:?}", sort_by)), |b| b.iter(|| {
+            let mut pixels_vec = pixels.clone();
+            sort_pixels(&sorter, &mut pixels_vec, &contrast_map, &contrast_values, SIZE as usize, SIZE as usize).unwrap();
+            pixels_vec
+        }));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_sort_keys);
+criterion_main!(benches);
@@ -0,0 +1,292 @@
+use std::sync::OnceLock;
+use image::Rgba;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use crate::DistanceSpace;
+
+/// Converts a pixel to HSV, returning hue in degrees (0-360) and saturation and value as percentages (0-100).
+pub fn rgb_to_hsv(colour: &Rgba<u8>) -> (f32, f32, f32) {
+    let r: f32 = colour[0] as f32/255.0;
+    let g: f32 = colour[1] as f32/255.0;
+    let b: f32 = colour[2] as f32/255.0;
+
+    let max: f32 = r.max(g).max(b);
+    let min: f32 = r.min(g).min(b);
+    let delta: f32 = max - min;
+
+    let mut h: f32 = 0.0;
+    if max == min {
+        h = 0.0;
+    } else if max == r {
+        h = (60.0 * ((g - b) / delta) + 360.0) % 360.0;
+    } else if max == g {
+        h = (60.0 * ((b - r) / delta) + 120.0) % 360.0;
+    } else if max == b {
+        h = (60.0 * ((r - g) / delta) + 240.0) % 360.0;
+    }
+
+    let s: f32 = if max == 0.0 {
+        0.0
+    } else {
+        (delta / max) * 100.0
+    };
+
+    (h, s, max * 100.0)
+}
+
+/// Converts a pixel to HSL, returning hue in degrees (0-360) and saturation and lightness as percentages (0-100).
+pub fn rgb_to_hsl(colour: &Rgba<u8>) -> (f32, f32, f32) {
+    let (h, _, _) = rgb_to_hsv(colour);
+    let max = colour[0].max(colour[1]).max(colour[2]) as f32/255.0;
+    let min = colour[0].min(colour[1]).min(colour[2]) as f32/255.0;
+    let l = (max + min)/2.0;
+
+    let s = if max == min {
+        0.0
+    } else {
+        (max - min)/(1.0 - (2.0*l - 1.0).abs())*100.0
+    };
+
+    (h, s, l*100.0)
+}
+
+/// Undoes the sRGB gamma curve, giving the channel's linear light from 0.0 to 1.0.
+fn linear(channel: u8) -> f32 {
+    static TABLE: OnceLock<[f32; 256]> = OnceLock::new();
+    TABLE.get_or_init(|| std::array::from_fn(|i| {
+        let value = i as f32/255.0;
+        if value <= 0.04045 {
+            value/12.92
+        } else {
+            ((value + 0.055)/1.055).powf(2.4)
+        }
+    }))[channel as usize]
+}
+
+/// Rec. 709 relative luminance, the physical brightness of the pixel from 0.0 to 1.0.
+pub fn luminance(colour: &Rgba<u8>) -> f32 {
+    0.2126*linear(colour[0]) + 0.7152*linear(colour[1]) + 0.0722*linear(colour[2])
+}
+
+/// Rec. 709 luma, from 0.0 to 1.0. Unlike [`luminance`] it weighs the gamma encoded channels, so it follows how bright
+/// the pixel looks.
+pub(crate) fn luma(colour: &Rgba<u8>) -> f32 {
+    (0.2126*colour[0] as f32 + 0.7152*colour[1] as f32 + 0.0722*colour[2] as f32)/255.0
+}
+
+/// Converts a pixel to CIE L*a*b* (D65 white), returning lightness from 0 to 100 and a and b roughly -128 to 128.
+pub fn rgb_to_lab(colour: &Rgba<u8>) -> (f32, f32, f32) {
+    let (r, g, b) = (linear(colour[0]) as f64, linear(colour[1]) as f64, linear(colour[2]) as f64);
+    let x = (0.4124564*r + 0.3575761*g + 0.1804375*b)/0.95047;
+    let y = 0.2126729*r + 0.7151522*g + 0.0721750*b;
+    let z = (0.0193339*r + 0.1191920*g + 0.9503041*b)/1.08883;
+
+    let f = |t: f64| if t > 216.0/24389.0 {t.cbrt()} else {(24389.0/27.0*t + 16.0)/116.0};
+    let (fx, fy, fz) = (f(x), f(y), f(z));
+    ((116.0*fy - 16.0) as f32, (500.0*(fx - fy)) as f32, (200.0*(fy - fz)) as f32)
+}
+
+/// Converts a pixel to CIE LCh, returning lightness from 0 to 100, chroma from 0 to about 134 and hue in degrees (0-360).
+pub fn rgb_to_lch(colour: &Rgba<u8>) -> (f32, f32, f32) {
+    let (l, a, b) = rgb_to_lab(colour);
+    (l, a.hypot(b), hue_degrees(a, b))
+}
+
+/// Converts a pixel to Oklab, returning lightness from 0.0 to 1.0 and a and b roughly -0.4 to 0.4.
+pub fn rgb_to_oklab(colour: &Rgba<u8>) -> (f32, f32, f32) {
+    let (r, g, b) = (linear(colour[0]) as f64, linear(colour[1]) as f64, linear(colour[2]) as f64);
+    let l = (0.4122214708*r + 0.5363325363*g + 0.0514459929*b).cbrt();
+    let m = (0.2119034982*r + 0.6806995451*g + 0.1073969566*b).cbrt();
+    let s = (0.0883024619*r + 0.2817188376*g + 0.6299787005*b).cbrt();
+
+    (
+        (0.2104542553*l + 0.7936177850*m - 0.0040720468*s) as f32,
+        (1.9779984951*l - 2.4285922050*m + 0.4505937099*s) as f32,
+        (0.0259040371*l + 0.7827717662*m - 0.8086757660*s) as f32,
+    )
+}
+
+/// Converts a pixel to Oklab's polar form, returning lightness from 0.0 to 1.0, chroma from 0.0 to about 0.32 and hue
+/// in degrees (0-360).
+pub fn rgb_to_oklch(colour: &Rgba<u8>) -> (f32, f32, f32) {
+    let (l, a, b) = rgb_to_oklab(colour);
+    (l, a.hypot(b), hue_degrees(a, b))
+}
+
+/// The angle of a colour's a and b components in degrees (0-360).
+fn hue_degrees(a: f32, b: f32) -> f32 {
+    b.atan2(a).to_degrees().rem_euclid(360.0)
+}
+
+/// An sRGB colour, written in settings as "#rrggbb" or [r, g, b].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Colour(pub [u8; 3]);
+
+impl Colour {
+    fn to_rgba(self) -> Rgba<u8> {
+        Rgba([self.0[0], self.0[1], self.0[2], 255])
+    }
+}
+
+impl Serialize for Colour {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format!("#{:02x}{:02x}{:02x}", self.0[0], self.0[1], self.0[2]))
+    }
+}
+
+impl<'de> Deserialize<'de> for Colour {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Written {
+            Hex(String),
+            Channels([u8; 3]),
+        }
+
+        match Written::deserialize(deserializer) {
+            Ok(Written::Channels(channels)) => Ok(Colour(channels)),
+            Ok(Written::Hex(hex)) => {
+                let digits = hex.strip_prefix('#').unwrap_or(&hex);
+                let channel = |i: usize| digits.get(i..i + 2).and_then(|pair| u8::from_str_radix(pair, 16).ok());
+                match (digits.len(), channel(0), channel(2), channel(4)) {
+                    (6, Some(r), Some(g), Some(b)) => Ok(Colour([r, g, b])),
+                    _ => Err(de::Error::custom(format!("The colour \"{}\" is not valid. Please write it as \"#rrggbb\" in hex.", hex))),
+                }
+            },
+            Err(_) => Err(de::Error::custom("The colour is not valid. Please write it as \"#rrggbb\" or [r, g, b].")),
+        }
+    }
+}
+
+/// How far apart two colours are in the given space. RGB and Oklab distances are straight line distances (RGB on the
+/// 0-255 scale) and Lab distances are CIEDE2000 colour differences.
+pub fn colour_distance(space: DistanceSpace, colour: &Rgba<u8>, target: &Colour) -> f32 {
+    let target = target.to_rgba();
+    match space {
+        DistanceSpace::Rgb => (0..3).map(|i| (colour[i] as f32 - target[i] as f32).powi(2)).sum::<f32>().sqrt(),
+        DistanceSpace::Lab => delta_e_2000(rgb_to_lab(colour), rgb_to_lab(&target)),
+        DistanceSpace::Oklab => {
+            let ((l1, a1, b1), (l2, a2, b2)) = (rgb_to_oklab(colour), rgb_to_oklab(&target));
+            ((l1 - l2).powi(2) + (a1 - a2).powi(2) + (b1 - b2).powi(2)).sqrt()
+        },
+    }
+}
+
+/// The CIEDE2000 colour difference between two L*a*b* colours, where 1.0 is about the smallest difference people notice.
+fn delta_e_2000((l1, a1, b1): (f32, f32, f32), (l2, a2, b2): (f32, f32, f32)) -> f32 {
+    let (l1, a1, b1, l2, a2, b2) = (l1 as f64, a1 as f64, b1 as f64, l2 as f64, a2 as f64, b2 as f64);
+    let pow_25_7 = 25f64.powi(7);
+
+    // Stretch a* so neutral colours are told apart better
+    let mean_chroma = (a1.hypot(b1) + a2.hypot(b2))/2.0;
+    let g = 0.5*(1.0 - (mean_chroma.powi(7)/(mean_chroma.powi(7) + pow_25_7)).sqrt());
+    let (a1, a2) = ((1.0 + g)*a1, (1.0 + g)*a2);
+    let (c1, c2) = (a1.hypot(b1), a2.hypot(b2));
+    let hue = |a: f64, b: f64| if a == 0.0 && b == 0.0 {0.0} else {b.atan2(a).to_degrees().rem_euclid(360.0)};
+    let (h1, h2) = (hue(a1, b1), hue(a2, b2));
+
+    let delta_l = l2 - l1;
+    let delta_c = c2 - c1;
+    let delta_h = if c1*c2 == 0.0 {
+        0.0
+    } else if (h2 - h1).abs() <= 180.0 {
+        h2 - h1
+    } else if h2 - h1 > 180.0 {
+        h2 - h1 - 360.0
+    } else {
+        h2 - h1 + 360.0
+    };
+    let delta_h = 2.0*(c1*c2).sqrt()*(delta_h/2.0).to_radians().sin();
+
+    let mean_l = (l1 + l2)/2.0;
+    let mean_c = (c1 + c2)/2.0;
+    let mean_h = if c1*c2 == 0.0 {
+        h1 + h2
+    } else if (h1 - h2).abs() <= 180.0 {
+        (h1 + h2)/2.0
+    } else if h1 + h2 < 360.0 {
+        (h1 + h2 + 360.0)/2.0
+    } else {
+        (h1 + h2 - 360.0)/2.0
+    };
+
+    let cos_degrees = |degrees: f64| degrees.to_radians().cos();
+    let t = 1.0 - 0.17*cos_degrees(mean_h - 30.0) + 0.24*cos_degrees(2.0*mean_h) + 0.32*cos_degrees(3.0*mean_h + 6.0) - 0.20*cos_degrees(4.0*mean_h - 63.0);
+    let delta_theta = 30.0*(-((mean_h - 275.0)/25.0).powi(2)).exp();
+    let r_c = 2.0*(mean_c.powi(7)/(mean_c.powi(7) + pow_25_7)).sqrt();
+    let s_l = 1.0 + 0.015*(mean_l - 50.0).powi(2)/(20.0 + (mean_l - 50.0).powi(2)).sqrt();
+    let s_c = 1.0 + 0.045*mean_c;
+    let s_h = 1.0 + 0.015*mean_c*t;
+    let r_t = -(2.0*delta_theta).to_radians().sin()*r_c;
+
+    let (l, c, h) = (delta_l/s_l, delta_c/s_c, delta_h/s_h);
+    (l*l + c*c + h*h + r_t*c*h).sqrt() as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgba;
+    use super::{delta_e_2000, luminance, rgb_to_lab, rgb_to_oklab};
+
+    const WHITE: Rgba<u8> = Rgba([255, 255, 255, 255]);
+    const BLACK: Rgba<u8> = Rgba([0, 0, 0, 255]);
+    const RED: Rgba<u8> = Rgba([255, 0, 0, 255]);
+    const BLUE: Rgba<u8> = Rgba([0, 0, 255, 255]);
+
+    fn assert_close((l, a, b): (f32, f32, f32), (expected_l, expected_a, expected_b): (f32, f32, f32), tolerance: f32) {
+        assert!((l - expected_l).abs() < tolerance && (a - expected_a).abs() < tolerance && (b - expected_b).abs() < tolerance,
+            "({}, {}, {}) is not ({}, {}, {})", l, a, b, expected_l, expected_a, expected_b);
+    }
+
+    #[test]
+    fn lab_matches_reference_conversions() {
+        assert_close(rgb_to_lab(&WHITE), (100.0, 0.0, 0.0), 0.01);
+        assert_close(rgb_to_lab(&BLACK), (0.0, 0.0, 0.0), 0.01);
+        assert_close(rgb_to_lab(&RED), (53.2408, 80.0925, 67.2032), 0.01);
+        assert_close(rgb_to_lab(&BLUE), (32.2970, 79.1875, -107.8602), 0.01);
+    }
+
+    #[test]
+    fn oklab_matches_reference_conversions() {
+        assert_close(rgb_to_oklab(&WHITE), (1.0, 0.0, 0.0), 0.0001);
+        assert_close(rgb_to_oklab(&BLACK), (0.0, 0.0, 0.0), 0.0001);
+        assert_close(rgb_to_oklab(&RED), (0.627955, 0.224863, 0.125846), 0.0001);
+        assert_close(rgb_to_oklab(&BLUE), (0.452014, -0.032457, -0.311528), 0.0001);
+    }
+
+    #[test]
+    fn luminance_matches_the_rec_709_weights() {
+        assert_eq!(luminance(&WHITE), 1.0);
+        assert_eq!(luminance(&BLACK), 0.0);
+        assert!((luminance(&RED) - 0.2126).abs() < 1e-6);
+        assert!((luminance(&BLUE) - 0.0722).abs() < 1e-6);
+        assert!((luminance(&Rgba([188, 188, 188, 255])) - 0.5).abs() < 0.01);
+    }
+
+    #[test]
+    fn delta_e_2000_matches_sharmas_test_data() {
+        // Pairs from Sharma, Wu and Dalal's CIEDE2000 test data, including the pairs where the mean hue wraps round
+        let pairs = [
+            ((50.0, 2.6772, -79.7751), (50.0, 0.0, -82.7485), 2.0425),
+            ((50.0, 3.1571, -77.2803), (50.0, 0.0, -82.7485), 2.8615),
+            ((50.0, 0.0, 0.0), (50.0, -1.0, 2.0), 2.3669),
+            ((50.0, -1.0, 2.0), (50.0, 0.0, 0.0), 2.3669),
+            ((50.0, 2.49, -0.001), (50.0, -2.49, 0.0009), 7.1792),
+            ((50.0, 2.49, -0.001), (50.0, -2.49, 0.0010), 7.1792),
+            ((50.0, 2.49, -0.001), (50.0, -2.49, 0.0011), 7.2195),
+            ((50.0, 2.49, -0.001), (50.0, -2.49, 0.0012), 7.2195),
+            ((50.0, -0.001, 2.49), (50.0, 0.0009, -2.49), 4.8045),
+            ((50.0, -0.001, 2.49), (50.0, 0.0010, -2.49), 4.8045),
+            ((50.0, -0.001, 2.49), (50.0, 0.0011, -2.49), 4.7461),
+            ((50.0, 2.5, 0.0), (50.0, 0.0, -2.5), 4.3065),
+            ((50.0, 2.5, 0.0), (73.0, 25.0, -18.0), 27.1492),
+            ((50.0, 2.5, 0.0), (50.0, 3.1736, 0.5854), 1.0),
+            ((60.2574, -34.0099, 36.2677), (60.4626, -34.1751, 39.4387), 1.2644),
+            ((63.0109, -31.0961, -5.8663), (62.8187, -29.7946, -4.0864), 1.2630),
+            ((22.7233, 20.0904, -46.6940), (23.0331, 14.9730, -42.5619), 2.0373),
+        ];
+        for (first, second, expected) in pairs {
+            let difference = delta_e_2000(first, second);
+            assert!((difference - expected).abs() < 1e-4, "{:?} to {:?} gave {}, not {}", first, second, difference, expected);
+        }
+    }
+}
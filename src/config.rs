@@ -0,0 +1,315 @@
+use std::fmt;
+use std::path::Path;
+use serde::de::{value, Deserialize, IntoDeserializer};
+use crate::{AutoThreshold, ContrastType, EdgeOperator, FlowSource, MaskCombine, MaskResize, MaskSource, ProgramSettings, SortBy, SortDirection, SortOrder, SpanMode, SpanSplit};
+
+/// The default config written when none exists. Every key is optional; missing keys fall back to these values.
+pub const DEFAULT_CONFIG: &str = r##"# The path where the image(s) are, as well as where the program will output the result
+input_path = "input"
+# The direction values increase in (Possible values: left, right, down, up, horizontal, vertical, { angle = 45.0 },
+# { radial = {} }, { concentric = {} }, { spiral = { spacing = 1.0 } }, with an optional centre = { fraction = [0.5, 0.5] } or { pixels = [x, y] },
+# { flow = { map = "flow.png" } }, { flow = { noise = { scale = 100.0 } } },
+# { wave = { axis = "horizontal", amplitude = 10.0, wavelength = 100.0, phase = 0.0 } }, serpentine, hilbert, zorder)
+sort_direction = "left"
+# How each span is ordered along the sort direction (Possible values: ascending, descending, alternating, random, mirror)
+sort_order = "ascending"
+# What value to sort by (Possible values: red, green, blue, hue, saturation, value, luminance, lightness, lab_lightness,
+# lch_chroma, lch_hue, oklab_lightness, oklab_chroma, oklab_hue, { expression = "max(r, g, b) - min(r, g, b)" },
+# { distance_to = { colour = "#008080", space = "lab" } } with space being rgb, lab or oklab)
+sort_by = "red"
+# Keys that order pixels whose earlier keys are equal, in turn, e.g. [{ key = "value", order = "descending" }]
+tie_breakers = []
+# Should pixels with exactly equal keys keep their original order? (Either true or false)
+stable_sort = false
+# The hue in degrees that hue keys start from, e.g. 300.0 keeps reds together (Range: 0.0-360.0)
+hue_origin = 0.0
+# The lower bound of values (Range: 0.0-1.0) (Anything more than this will get sorted)
+contrast_map_lower = 0.5
+# The upper bound of values (Range: 0.0-1.0) (Anything less than this will get sorted)
+contrast_map_upper = 1.0
+# Pick the lower bound for each image instead (Possible values: off, otsu (splits the values in two), or the fraction of
+# pixels to sort, e.g. { coverage = 0.35 }) (The bounds picked are printed and saved next to the sorted image)
+auto_threshold = "off"
+# More ranges of values to sort, on top of the bounds above, e.g. [[0.0, 0.1], [0.8, 0.9]] (A lower bound above the upper
+# one sorts everything outside the band, for the main bounds too)
+contrast_map_ranges = []
+# Should the pixels outside the ranges be sorted instead of those inside them? (Either true or false)
+invert_contrast_map = false
+# What value should be used to create the contrast map (Same values as sort_by, or a test across several of them that
+# replaces the bounds, e.g. { predicate = "value > 0.4 and saturation < 0.2" }, or how strong the edges are, e.g.
+# { edges = { operator = "sobel", blur = 1.0 } } with operator being sobel, scharr or canny (low = 0.05 and high = 0.1
+# set the Canny thresholds); bounds of 0.0 and 0.05 sort the spans between edges)
+contrast_type = "red"
+# A mask to sort with instead of, or as well as, the contrast map (Possible values: off, { image = "mask.png" } (a black
+# and white or greyscale image) or alpha (the alpha channel of each image))
+mask = "off"
+# The grey level (or alpha) a mask pixel needs to be in the mask (Range: 0.0-1.0)
+mask_threshold = 0.5
+# How the mask is combined with the contrast map (Possible values: replace (only the mask is used), and (pixels must be
+# in both), or (pixels can be in either))
+mask_combine = "replace"
+# What to do when a mask image is a different size to an image (Possible values: error (skip the image), stretch)
+mask_resize = "error"
+# How the spans that get sorted are found (Possible values: mask (every run of pixels in the contrast map), or
+# { hysteresis = { high = 0.8 } } to only start spans where the contrast value reaches high, carrying on while the
+# pixels stay in the contrast map, or { difference = { delta = 0.1 } } to also end spans wherever the contrast value
+# changes by more than delta from one pixel to the next)
+span_mode = "mask"
+# Spans shorter than this many pixels are left unsorted
+min_span_length = 0
+# Spans are cut into pieces at most this many pixels long (0 for no limit)
+max_span_length = 0
+# Cut spans into pieces of random length (Possible values: off, { uniform = { min = 20, max = 80 } } (lengths between min
+# and max pixels) or { exponential = { mean = 50.0 } } (mean pixels long on average, short pieces being more likely))
+span_split = "off"
+# Should the program print debug messages and create debug images? (Either true or false)
+should_debug = false
+# How many threads to sort with (0 uses every core)
+threads = 0
+# Seeds every random choice, change it to get a different result from the same settings
+seed = 0
+"##;
+
+/// The formats a settings file can be written in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigFormat {
+    Toml,
+    Json,
+    /// The original positional settings.txt, one value per line in a fixed order.
+    Legacy,
+}
+
+impl ConfigFormat {
+    /// Picks the format from the file extension, treating anything that isn't `.toml` or `.json` as a legacy file.
+    pub fn from_path(path: &Path) -> ConfigFormat {
+        match path.extension().and_then(|extension| extension.to_str()).map(|extension| extension.to_ascii_lowercase()).as_deref() {
+            Some("toml") => ConfigFormat::Toml,
+            Some("json") => ConfigFormat::Json,
+            _ => ConfigFormat::Legacy,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Toml(toml::de::Error),
+    Json(serde_json::Error),
+    Legacy(String),
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Toml(err) => write!(f, "The settings file is not valid TOML. {}", err),
+            ConfigError::Json(err) => write!(f, "The settings file is not valid JSON. {}", err),
+            ConfigError::Legacy(message) | ConfigError::Invalid(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Default for ProgramSettings {
+    fn default() -> Self {
+        ProgramSettings {
+            input_path: "input".to_string(),
+            sort_direction: SortDirection::Left,
+            sort_order: SortOrder::Ascending,
+            sort_by: SortBy::Red,
+            tie_breakers: Vec::new(),
+            stable_sort: false,
+            hue_origin: 0.0,
+            contrast_map_lower: 0.5,
+            contrast_map_upper: 1.0,
+            auto_threshold: AutoThreshold::Off,
+            contrast_map_ranges: Vec::new(),
+            invert_contrast_map: false,
+            contrast_type: ContrastType::Red,
+            mask: MaskSource::Off,
+            mask_threshold: 0.5,
+            mask_combine: MaskCombine::Replace,
+            mask_resize: MaskResize::Error,
+            span_mode: SpanMode::Mask,
+            min_span_length: 0,
+            max_span_length: 0,
+            span_split: SpanSplit::Off,
+            should_debug: false,
+            threads: 0,
+            seed: 0,
+        }
+    }
+}
+
+impl ProgramSettings {
+    /// Parses and validates a settings file's contents.
+    pub fn parse(contents: &str, format: ConfigFormat) -> Result<ProgramSettings, ConfigError> {
+        let program_settings = match format {
+            ConfigFormat::Toml => toml::from_str(contents).map_err(ConfigError::Toml)?,
+            ConfigFormat::Json => serde_json::from_str(contents).map_err(ConfigError::Json)?,
+            ConfigFormat::Legacy => Self::parse_legacy(contents)?,
+        };
+        program_settings.validate()?;
+        Ok(program_settings)
+    }
+
+    /// Reads the original 7-line settings.txt. `//` comments and blank lines are ignored.
+    fn parse_legacy(contents: &str) -> Result<ProgramSettings, ConfigError> {
+        let lines = contents.lines().map(|x| x.split("//").next().unwrap().trim().to_string()).filter(|x| !x.is_empty()).collect::<Vec<String>>();
+        if lines.len() != 7 {
+            return Err(ConfigError::Legacy(format!("The settings file is not formatted correctly. Expected 7 settings but found {}.", lines.len())));
+        }
+
+        let parse_bound = |line: &str, name: &str| {
+            line.parse::<f32>().map_err(|_| ConfigError::Legacy(format!("The contrast map {} bound is not valid. Please make sure the value is a number between 0.0 and 1.0.", name)))
+        };
+
+        Ok(ProgramSettings {
+            input_path: lines[0].clone(),
+            sort_direction: parse_enum(&lines[1].to_ascii_lowercase(), "sort direction")?,
+            sort_by: parse_enum(&lines[2].to_ascii_lowercase(), "sort by value")?,
+            contrast_map_lower: parse_bound(&lines[3], "lower")?,
+            contrast_map_upper: parse_bound(&lines[4], "upper")?,
+            contrast_type: parse_enum(&lines[5].to_ascii_lowercase(), "contrast type")?,
+            should_debug: lines[6].to_ascii_lowercase().parse().map_err(|_| ConfigError::Legacy("The debug value is not valid. Please make sure the value is either true or false.".to_string()))?,
+            ..ProgramSettings::default()
+        })
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        match &self.sort_direction {
+            SortDirection::Angle(degrees) if !degrees.is_finite() => {
+                return Err(ConfigError::Invalid("The sort direction angle is not valid. Please make sure the value is a number of degrees.".to_string()));
+            },
+            SortDirection::Spiral { spacing, .. } if !(*spacing > 0.0 && spacing.is_finite()) => {
+                return Err(ConfigError::Invalid("The spiral spacing is not valid. Please make sure the value is a number of pixels above 0.".to_string()));
+            },
+            SortDirection::Wave { amplitude, wavelength, phase, .. } if !(amplitude.is_finite() && *wavelength > 0.0 && wavelength.is_finite() && phase.is_finite()) => {
+                return Err(ConfigError::Invalid("The wave is not valid. Please make sure the amplitude and phase are numbers and the wavelength is a number of pixels above 0.".to_string()));
+            },
+            SortDirection::Flow(FlowSource::Noise { scale }) if !(*scale > 0.0 && scale.is_finite()) => {
+                return Err(ConfigError::Invalid("The flow noise scale is not valid. Please make sure the value is a number of pixels above 0.".to_string()));
+            },
+            _ => {},
+        }
+        if !self.hue_origin.is_finite() {
+            return Err(ConfigError::Invalid("The hue origin is not valid. Please make sure the value is a number of degrees.".to_string()));
+        }
+        // An expression's values aren't scaled, so its bounds can be any number
+        let expression = matches!(self.contrast_type, ContrastType::Expression(_));
+        let bound_is_valid = |bound: &f32| if expression {bound.is_finite()} else {(0.0..=1.0).contains(bound)};
+        let bound_range = if expression {"a number"} else {"a number between 0.0 and 1.0"};
+        if !bound_is_valid(&self.contrast_map_lower) {
+            return Err(ConfigError::Invalid(format!("The contrast map lower bound is not valid. Please make sure the value is {}.", bound_range)));
+        }
+        if !bound_is_valid(&self.contrast_map_upper) {
+            return Err(ConfigError::Invalid(format!("The contrast map upper bound is not valid. Please make sure the value is {}.", bound_range)));
+        }
+        if self.auto_threshold != AutoThreshold::Off && matches!(self.contrast_type, ContrastType::Predicate(_)) {
+            return Err(ConfigError::Invalid("The auto threshold can't be used with a predicate, which decides on its own what gets sorted. Please set auto_threshold to off.".to_string()));
+        }
+        if let AutoThreshold::Coverage(coverage) = self.auto_threshold {
+            if !(0.0..=1.0).contains(&coverage) {
+                return Err(ConfigError::Invalid("The auto threshold coverage is not valid. Please make sure the value is a number between 0.0 and 1.0.".to_string()));
+            }
+        }
+        if self.contrast_map_ranges.iter().any(|(lower, upper)| !bound_is_valid(lower) || !bound_is_valid(upper)) {
+            return Err(ConfigError::Invalid(format!("A contrast map range is not valid. Please make sure both bounds are {}.", bound_range)));
+        }
+        if let ContrastType::Edges { operator, blur, low, high } = self.contrast_type {
+            if !blur.is_finite() || blur < 0.0 {
+                return Err(ConfigError::Invalid("The edge blur is not valid. Please make sure the value is a number of pixels, 0.0 or above.".to_string()));
+            }
+            if operator == EdgeOperator::Canny && !(0.0 <= low && low <= high && high <= 1.0) {
+                return Err(ConfigError::Invalid("The edge thresholds are not valid. Please make sure low and high are numbers between 0.0 and 1.0, with low no more than high.".to_string()));
+            }
+        }
+        if !(0.0..=1.0).contains(&self.mask_threshold) {
+            return Err(ConfigError::Invalid("The mask threshold is not valid. Please make sure the value is a number between 0.0 and 1.0.".to_string()));
+        }
+        if let SpanMode::Hysteresis { high } = self.span_mode {
+            if !high.is_finite() {
+                return Err(ConfigError::Invalid("The hysteresis high threshold is not valid. Please make sure the value is a number.".to_string()));
+            }
+        }
+        if let SpanMode::Difference { delta } = self.span_mode {
+            if !delta.is_finite() || delta < 0.0 {
+                return Err(ConfigError::Invalid("The span difference delta is not valid. Please make sure the value is a number, 0.0 or above.".to_string()));
+            }
+        }
+        match self.span_split {
+            SpanSplit::Uniform { min, max } if min == 0 || min > max => {
+                return Err(ConfigError::Invalid("The uniform span split is not valid. Please make sure min is at least 1 and no more than max.".to_string()));
+            }
+            SpanSplit::Exponential { mean } if !mean.is_finite() || mean < 1.0 => {
+                return Err(ConfigError::Invalid("The exponential span split is not valid. Please make sure the mean is a number of pixels, 1.0 or above.".to_string()));
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    pub fn to_toml(&self) -> String {
+        toml::to_string(self).unwrap()
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+}
+
+/// Reads a legacy enum value (e.g. "left") through the same names the keyed formats use.
+fn parse_enum<T: for<'de> Deserialize<'de>>(line: &str, name: &str) -> Result<T, ConfigError> {
+    T::deserialize(IntoDeserializer::<value::Error>::into_deserializer(line)).map_err(|_| ConfigError::Legacy(format!("The {} is not valid. Please make sure the value is supported and spelt correctly.", name)))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{ContrastType, ProgramSettings, SortBy, SortDirection};
+    use super::{ConfigError, ConfigFormat, DEFAULT_CONFIG};
+
+    #[test]
+    fn the_default_config_is_the_default_settings() {
+        assert_eq!(ProgramSettings::parse(DEFAULT_CONFIG, ConfigFormat::Toml).unwrap(), ProgramSettings::default());
+    }
+
+    #[test]
+    fn legacy_files_skip_comments_and_blank_lines() {
+        let contents = "// Where the images are\nPhotos/Summer Trip\n\nDOWN // the sort direction\nHue\n0.25\n  0.75  \n\n// The contrast type\nvalue\nTrue\n";
+        let expected = ProgramSettings {
+            input_path: "Photos/Summer Trip".to_string(),
+            sort_direction: SortDirection::Down,
+            sort_by: SortBy::Hue,
+            contrast_map_lower: 0.25,
+            contrast_map_upper: 0.75,
+            contrast_type: ContrastType::Value,
+            should_debug: true,
+            ..ProgramSettings::default()
+        };
+        assert_eq!(ProgramSettings::parse(contents, ConfigFormat::Legacy).unwrap(), expected);
+        assert!(matches!(ProgramSettings::parse("input\nleft\nred\n0.5\n1.0\nred\n", ConfigFormat::Legacy), Err(ConfigError::Legacy(_))));
+    }
+
+    #[test]
+    fn missing_keys_take_their_defaults() {
+        let expected = ProgramSettings { sort_direction: SortDirection::Up, contrast_map_lower: 0.2, ..ProgramSettings::default() };
+        assert_eq!(ProgramSettings::parse("sort_direction = \"up\"\ncontrast_map_lower = 0.2\n", ConfigFormat::Toml).unwrap(), expected);
+        assert_eq!(ProgramSettings::parse("{\"sort_direction\": \"up\", \"contrast_map_lower\": 0.2}", ConfigFormat::Json).unwrap(), expected);
+        assert_eq!(ProgramSettings::parse("", ConfigFormat::Toml).unwrap(), ProgramSettings::default());
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        let err = ProgramSettings::parse("sort_direction = \"up\"\nsort_dirction = \"down\"\n", ConfigFormat::Toml).unwrap_err();
+        assert!(matches!(&err, ConfigError::Toml(_)));
+        assert!(err.to_string().contains("sort_dirction"), "{}", err);
+        assert!(matches!(ProgramSettings::parse("{\"treads\": 2}", ConfigFormat::Json), Err(ConfigError::Json(_))));
+    }
+
+    #[test]
+    fn predicates_cant_have_an_auto_threshold() {
+        let contents = "contrast_type = { predicate = \"value > 0.5\" }\nauto_threshold = \"otsu\"\n";
+        assert!(matches!(ProgramSettings::parse(contents, ConfigFormat::Toml), Err(ConfigError::Invalid(_))));
+        assert!(ProgramSettings::parse("contrast_type = { predicate = \"value > 0.5\" }\n", ConfigFormat::Toml).is_ok());
+    }
+}
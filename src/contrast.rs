@@ -0,0 +1,225 @@
+use image::Rgba;
+use crate::edges::edge_values;
+use crate::{colour_distance, AutoThreshold, luminance, rgb_to_hsl, rgb_to_hsv, rgb_to_lab, rgb_to_lch, rgb_to_oklch, ContrastType, DistanceSpace, PixelSorter};
+
+/// The most chroma any sRGB colour has in CIE LCh (pure blue).
+const MAX_LCH_CHROMA: f32 = 133.81;
+
+/// The most chroma any sRGB colour has in Oklab (pure magenta).
+const MAX_OKLAB_CHROMA: f32 = 0.3225;
+
+/// Builds the contrast map, `true` for every pixel whose contrast value lies in the bounds or any of the other ranges
+/// (or in none of them when the map is inverted). A predicate decides on its own, without the bounds.
+pub fn create_contrast_map(sorter: &PixelSorter, pixels_vec: &[Rgba<u8>], width: usize, height: usize) -> Vec<bool> {
+    let values = contrast_values(&sorter.settings.contrast_type, pixels_vec, width, height);
+    map_contrast_values(sorter, &values).0
+}
+
+/// Builds the contrast map from every pixel's contrast value, along with the (lower, upper) bounds it used, which
+/// [`AutoThreshold`] picks from the values.
+pub(crate) fn map_contrast_values(sorter: &PixelSorter, values: &[f32]) -> (Vec<bool>, (f32, f32)) {
+    let bounds = (sorter.settings.contrast_map_lower, sorter.settings.contrast_map_upper);
+    if let ContrastType::Predicate(_) = &sorter.settings.contrast_type {
+        // A predicate's value is 1.0 where it matches and 0.0 elsewhere
+        return (values.iter().map(|value| (*value == 1.0) != sorter.settings.invert_contrast_map).collect(), bounds);
+    }
+
+    let bounds = match sorter.settings.auto_threshold {
+        AutoThreshold::Off => bounds,
+        AutoThreshold::Otsu => (otsu_threshold(values, sorter.settings.contrast_map_upper), sorter.settings.contrast_map_upper),
+        AutoThreshold::Coverage(coverage) => {
+            // Inverting sorts what is outside the bounds, so that is what has to make up the coverage
+            let coverage = if sorter.settings.invert_contrast_map {1.0 - coverage} else {coverage};
+            (coverage_threshold(values, coverage, sorter.settings.contrast_map_upper), sorter.settings.contrast_map_upper)
+        },
+    };
+    let mut ranges = vec![bounds];
+    ranges.extend_from_slice(&sorter.settings.contrast_map_ranges);
+    let contrast_map = values.iter().map(|value| {
+        ranges.iter().any(|range| in_range(*value, *range)) != sorter.settings.invert_contrast_map
+    }).collect();
+    (contrast_map, bounds)
+}
+
+/// Otsu's method: the value splitting a 256 bucket histogram of the values up to the upper bound into the two groups
+/// whose spread, weighted by their size, is smallest. Values at or above it are in the upper group. It is never above
+/// the upper bound, so the bounds can't swap over into taking everything outside the band.
+fn otsu_threshold(values: &[f32], upper: f32) -> f32 {
+    let values: Vec<f32> = values.iter().copied().filter(|value| *value <= upper).collect();
+    if values.is_empty() {
+        return upper;
+    }
+    let (min, max) = values.iter().fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), value| (min.min(*value), max.max(*value)));
+    if min >= max {
+        return min;
+    }
+
+    let bucket_width = (max - min)/256.0;
+    let mut histogram = [0u64; 256];
+    for value in &values {
+        histogram[(((value - min)/bucket_width) as usize).min(255)] += 1;
+    }
+
+    let total = values.len() as f64;
+    let sum: f64 = histogram.iter().enumerate().map(|(bucket, count)| bucket as f64*(*count as f64)).sum();
+    let (mut lower_count, mut lower_sum) = (0.0, 0.0);
+    let (mut best_bucket, mut best_variance) = (0, -1.0);
+    // The split goes after each bucket in turn, keeping the one with the most variance between the two groups
+    for (bucket, count) in histogram.iter().enumerate().take(255) {
+        lower_count += *count as f64;
+        lower_sum += bucket as f64*(*count as f64);
+        let upper_count = total - lower_count;
+        if lower_count == 0.0 || upper_count == 0.0 {
+            continue;
+        }
+        let difference = lower_sum/lower_count - (sum - lower_sum)/upper_count;
+        let variance = lower_count*upper_count*difference*difference;
+        if variance > best_variance {
+            best_bucket = bucket;
+            best_variance = variance;
+        }
+    }
+    min + (best_bucket + 1) as f32*bucket_width
+}
+
+/// The lower bound that puts as close to `coverage` of the values as it can between it and the upper bound.
+fn coverage_threshold(values: &[f32], coverage: f32, upper: f32) -> f32 {
+    let mut candidates: Vec<f32> = values.iter().copied().filter(|value| *value <= upper).collect();
+    if candidates.is_empty() {
+        return upper;
+    }
+    let count = ((coverage as f64*values.len() as f64).round() as usize).clamp(1, candidates.len());
+    // The count-th largest value, so that value and everything above it up to the upper bound is covered
+    let (_, lower, _) = candidates.select_nth_unstable_by(count - 1, |a, b| b.total_cmp(a));
+    *lower
+}
+
+/// The contrast value of every pixel in a row-major image. Unlike [`contrast_value`], this can look at a pixel's
+/// neighbours, which [`ContrastType::Edges`] needs.
+pub fn contrast_values(contrast_type: &ContrastType, pixels_vec: &[Rgba<u8>], width: usize, height: usize) -> Vec<f32> {
+    match contrast_type {
+        ContrastType::Edges { operator, blur, low, high } => edge_values(*operator, *blur, *low, *high, pixels_vec, width, height),
+        _ => pixels_vec.iter().map(|pixel| contrast_value(contrast_type, pixel)).collect(),
+    }
+}
+
+/// Whether the value lies between the bounds. A lower bound above the upper one takes everything outside the band
+/// instead, which for hues is a range that wraps past 360° back to 0°.
+fn in_range(value: f32, (lower, upper): (f32, f32)) -> bool {
+    if lower > upper {
+        value >= lower || value <= upper
+    } else {
+        value >= lower && value <= upper
+    }
+}
+
+/// The value the contrast map bounds are compared against, scaled to 0.0-1.0 (except for expressions, which are used as they are).
+/// Edges can't be found from one pixel, so they are 0.0 here; use [`contrast_values`] for them.
+pub fn contrast_value(contrast_type: &ContrastType, pixel: &Rgba<u8>) -> f32 {
+    match contrast_type {
+        ContrastType::Red => pixel[0] as f32/255.0,
+        ContrastType::Green => pixel[1] as f32/255.0,
+        ContrastType::Blue => pixel[2] as f32/255.0,
+        ContrastType::Hue => rgb_to_hsv(pixel).0/360.0,
+        ContrastType::Saturation => rgb_to_hsv(pixel).1/100.0,
+        ContrastType::Value => rgb_to_hsv(pixel).2/100.0,
+        ContrastType::Luminance => luminance(pixel),
+        ContrastType::Lightness => rgb_to_hsl(pixel).2/100.0,
+        ContrastType::LabLightness => (rgb_to_lab(pixel).0/100.0).min(1.0),
+        ContrastType::LchChroma => (rgb_to_lch(pixel).1/MAX_LCH_CHROMA).min(1.0),
+        ContrastType::LchHue => rgb_to_lch(pixel).2/360.0,
+        ContrastType::OklabLightness => rgb_to_oklch(pixel).0.min(1.0),
+        ContrastType::OklabChroma => (rgb_to_oklch(pixel).1/MAX_OKLAB_CHROMA).min(1.0),
+        ContrastType::OklabHue => rgb_to_oklch(pixel).2/360.0,
+        ContrastType::Expression(expression) => expression.evaluate(pixel),
+        ContrastType::Predicate(predicate) => if predicate.matches(pixel) {1.0} else {0.0},
+        ContrastType::Edges { .. } => 0.0,
+        ContrastType::DistanceTo { colour, space } => (colour_distance(*space, pixel, colour)/max_distance(*space)).min(1.0),
+    }
+}
+
+/// The distance from black to white in each space, which distances are scaled by.
+fn max_distance(space: DistanceSpace) -> f32 {
+    match space {
+        DistanceSpace::Rgb => 255.0*3f32.sqrt(),
+        DistanceSpace::Lab => 100.0,
+        DistanceSpace::Oklab => 1.0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgba, RgbaImage};
+    use crate::{rgb_to_lch, rgb_to_oklch, AutoThreshold, ContrastType, PixelSorter};
+    use super::{MAX_LCH_CHROMA, MAX_OKLAB_CHROMA};
+
+    /// Every red level from 0 to 255 once, in a row.
+    fn red_ramp() -> RgbaImage {
+        RgbaImage::from_fn(256, 1, |x, _| Rgba([x as u8, 0, 0, 255]))
+    }
+
+    #[test]
+    fn chroma_scales_are_the_most_chroma_any_srgb_colour_has() {
+        // The most saturated colours are on the outside of the RGB cube, where a channel is 0 or 255
+        let surface = (0..6u32).flat_map(|face| (0..=255u8).flat_map(move |u| (0..=255u8).map(move |v| {
+            let axis = face as usize % 3;
+            let mut channels = [if face < 3 {0} else {255}; 3];
+            channels[(axis + 1) % 3] = u;
+            channels[(axis + 2) % 3] = v;
+            Rgba([channels[0], channels[1], channels[2], 255])
+        })));
+        let (lch_chroma, oklab_chroma) = surface.fold((0.0f32, 0.0f32), |(lch, oklab), pixel| {
+            (lch.max(rgb_to_lch(&pixel).1), oklab.max(rgb_to_oklch(&pixel).1))
+        });
+        assert!(lch_chroma <= MAX_LCH_CHROMA && lch_chroma > MAX_LCH_CHROMA - 0.01, "the most LCh chroma is {}", lch_chroma);
+        assert!(oklab_chroma <= MAX_OKLAB_CHROMA && oklab_chroma > MAX_OKLAB_CHROMA - 0.0001, "the most Oklab chroma is {}", oklab_chroma);
+    }
+
+    #[test]
+    fn otsu_never_picks_a_lower_bound_above_the_upper_one() {
+        let sorter = PixelSorter::new().contrast_type(ContrastType::Red).contrast_bounds(0.0, 0.2).auto_threshold(AutoThreshold::Otsu);
+        let (contrast_map, (lower, upper)) = sorter.contrast_map_with_bounds(&red_ramp()).unwrap();
+        assert!(lower <= upper, "picked {} to {}", lower, upper);
+        assert!(contrast_map.iter().skip(52).all(|in_map| !in_map), "values above the upper bound were sorted");
+    }
+
+    #[test]
+    fn coverage_counts_the_pixels_sorted_whether_or_not_the_map_is_inverted() {
+        for invert in [false, true] {
+            let sorter = PixelSorter::new()
+                .contrast_type(ContrastType::Red)
+                .contrast_bounds(0.0, 1.0)
+                .auto_threshold(AutoThreshold::Coverage(0.25))
+                .invert_contrast_map(invert);
+            let contrast_map = sorter.contrast_map(&red_ramp()).unwrap();
+            assert_eq!(contrast_map.iter().filter(|in_map| **in_map).count(), 64, "inverted: {}", invert);
+        }
+    }
+
+    /// Which of the sample red levels are in the contrast map of the ramp.
+    fn sampled(sorter: &PixelSorter) -> Vec<bool> {
+        let contrast_map = sorter.clone().contrast_type(ContrastType::Red).contrast_map(&red_ramp()).unwrap();
+        [0, 13, 64, 115, 160, 242, 255].iter().map(|red| contrast_map[*red]).collect()
+    }
+
+    #[test]
+    fn a_lower_bound_above_the_upper_one_takes_everything_outside_the_band() {
+        assert_eq!(sampled(&PixelSorter::new().contrast_bounds(0.2, 0.8)), [false, false, true, true, true, false, false]);
+        assert_eq!(sampled(&PixelSorter::new().contrast_bounds(0.8, 0.2)), [true, true, false, false, false, true, true]);
+    }
+
+    #[test]
+    fn extra_ranges_are_sorted_as_well_as_the_bounds() {
+        let sorter = PixelSorter::new().contrast_bounds(0.9, 1.0).contrast_ranges(vec![(0.0, 0.1), (0.4, 0.5)]);
+        assert_eq!(sampled(&sorter), [true, true, false, true, false, true, true]);
+    }
+
+    #[test]
+    fn inverting_sorts_what_is_in_none_of_the_ranges() {
+        let sorter = PixelSorter::new().contrast_bounds(0.9, 1.0).contrast_ranges(vec![(0.0, 0.1), (0.4, 0.5)]).invert_contrast_map(true);
+        assert_eq!(sampled(&sorter), [false, false, true, false, true, false, false]);
+        // A wrapped band inverts to the band itself
+        let sorter = PixelSorter::new().contrast_bounds(0.8, 0.2).invert_contrast_map(true);
+        assert_eq!(sampled(&sorter), [false, false, true, true, true, false, false]);
+    }
+}
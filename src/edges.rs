@@ -0,0 +1,161 @@
+use std::collections::VecDeque;
+use image::Rgba;
+use rayon::prelude::*;
+use crate::colour::luma;
+use crate::EdgeOperator;
+
+/// How strong the edge at every pixel is, from 0.0 to 1.0. Sobel and Scharr give the gradient magnitude, scaled so a
+/// hard black to white step is 1.0. Canny gives 1.0 on the thin edges it keeps and 0.0 everywhere else. The image is
+/// blurred by a Gaussian with a standard deviation of `blur` pixels first, to keep noise from showing up as edges.
+pub fn edge_values(operator: EdgeOperator, blur: f32, low: f32, high: f32, pixels_vec: &[Rgba<u8>], width: usize, height: usize) -> Vec<f32> {
+    // Luma rather than luminance, so edges follow how the image looks
+    let grey: Vec<f32> = pixels_vec.par_iter().map(luma).collect();
+    let grey = gaussian_blur(grey, width, height, blur as f64);
+
+    let (corner, middle) = match operator {
+        EdgeOperator::Sobel | EdgeOperator::Canny => (1.0, 2.0),
+        EdgeOperator::Scharr => (3.0, 10.0),
+    };
+    let gradients = gradients(&grey, width, height, corner, middle);
+    let magnitudes: Vec<f32> = gradients.par_iter().map(|(gx, gy)| gx.hypot(*gy).min(1.0)).collect();
+
+    match operator {
+        EdgeOperator::Sobel | EdgeOperator::Scharr => magnitudes,
+        EdgeOperator::Canny => canny(&magnitudes, &gradients, width, height, low, high),
+    }
+}
+
+/// The value at (x, y), with coordinates outside the image clamped to its edge.
+fn sample(values: &[f32], width: usize, height: usize, x: isize, y: isize) -> f32 {
+    let x = x.clamp(0, width as isize - 1) as usize;
+    let y = y.clamp(0, height as isize - 1) as usize;
+    values[y*width + x]
+}
+
+/// Blurs horizontally and then vertically, which is the same as blurring with the full 2D Gaussian.
+fn gaussian_blur(values: Vec<f32>, width: usize, height: usize, sigma: f64) -> Vec<f32> {
+    if sigma <= 0.0 {
+        return values;
+    }
+    let radius = (3.0*sigma).ceil() as isize;
+    let weights: Vec<f64> = (-radius..=radius).map(|offset| (-(offset*offset) as f64/(2.0*sigma*sigma)).exp()).collect();
+    let total: f64 = weights.iter().sum();
+    let weights: Vec<f32> = weights.iter().map(|weight| (weight/total) as f32).collect();
+
+    let pass = |values: &[f32], (step_x, step_y): (isize, isize)| -> Vec<f32> {
+        (0..width*height).into_par_iter().map(|i| {
+            let (x, y) = ((i % width) as isize, (i / width) as isize);
+            weights.iter().zip(-radius..=radius).map(|(weight, offset)| weight*sample(values, width, height, x + offset*step_x, y + offset*step_y)).sum()
+        }).collect()
+    };
+    let horizontal = pass(&values, (1, 0));
+    pass(&horizontal, (0, 1))
+}
+
+/// The x and y gradients from a 3x3 Sobel style kernel with the given corner and middle weights, scaled so a hard
+/// step from 0.0 to 1.0 has a gradient of 1.0.
+fn gradients(grey: &[f32], width: usize, height: usize, corner: f32, middle: f32) -> Vec<(f32, f32)> {
+    let scale = 2.0*corner + middle;
+    (0..width*height).into_par_iter().map(|i| {
+        let (x, y) = ((i % width) as isize, (i / width) as isize);
+        let at = |dx: isize, dy: isize| sample(grey, width, height, x + dx, y + dy);
+        let gx = corner*(at(1, -1) - at(-1, -1)) + middle*(at(1, 0) - at(-1, 0)) + corner*(at(1, 1) - at(-1, 1));
+        let gy = corner*(at(-1, 1) - at(-1, -1)) + middle*(at(0, 1) - at(0, -1)) + corner*(at(1, 1) - at(1, -1));
+        (gx/scale, gy/scale)
+    }).collect()
+}
+
+/// Thins the gradient magnitudes down to one pixel wide edges, then keeps the edges at least `high` strong along with
+/// any at least `low` strong that connect to them.
+fn canny(magnitudes: &[f32], gradients: &[(f32, f32)], width: usize, height: usize, low: f32, high: f32) -> Vec<f32> {
+    // Only keep pixels that are stronger than their neighbours across the edge
+    let thin: Vec<f32> = (0..width*height).into_par_iter().map(|i| {
+        let magnitude = magnitudes[i];
+        if magnitude == 0.0 {
+            return 0.0;
+        }
+        let (x, y) = ((i % width) as isize, (i / width) as isize);
+        let (gx, gy) = gradients[i];
+        let angle = gy.atan2(gx).to_degrees().rem_euclid(180.0);
+        let (dx, dy) = if !(22.5..157.5).contains(&angle) {
+            (1, 0)
+        } else if angle < 67.5 {
+            (1, 1)
+        } else if angle < 112.5 {
+            (0, 1)
+        } else {
+            (-1, 1)
+        };
+        let ahead = sample(magnitudes, width, height, x + dx, y + dy);
+        let behind = sample(magnitudes, width, height, x - dx, y - dy);
+        // Ties only go one way, so a flat ridge stays one pixel wide
+        if magnitude > ahead && magnitude >= behind {magnitude} else {0.0}
+    }).collect();
+
+    let mut edges = vec![0.0; width*height];
+    let mut queue: VecDeque<usize> = (0..width*height).filter(|i| thin[*i] >= high).collect();
+    queue.iter().for_each(|i| edges[*i] = 1.0);
+    while let Some(i) = queue.pop_front() {
+        let (x, y) = ((i % width) as isize, (i / width) as isize);
+        for (dx, dy) in [(-1, -1), (0, -1), (1, -1), (-1, 0), (1, 0), (-1, 1), (0, 1), (1, 1)] {
+            let (nx, ny) = (x + dx, y + dy);
+            if nx < 0 || ny < 0 || nx >= width as isize || ny >= height as isize {
+                continue;
+            }
+            let neighbour = ny as usize*width + nx as usize;
+            if edges[neighbour] == 0.0 && thin[neighbour] >= low {
+                edges[neighbour] = 1.0;
+                queue.push_back(neighbour);
+            }
+        }
+    }
+    edges
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgba;
+    use crate::EdgeOperator;
+    use super::edge_values;
+
+    /// A width by height image with the grey level from the function at each pixel.
+    fn image(width: usize, height: usize, grey: impl Fn(usize, usize) -> u8) -> Vec<Rgba<u8>> {
+        (0..width*height).map(|i| {
+            let level = grey(i % width, i / width);
+            Rgba([level, level, level, 255])
+        }).collect()
+    }
+
+    #[test]
+    fn a_hard_step_is_a_full_strength_edge() {
+        let step = image(8, 8, |x, _| if x < 4 {0} else {255});
+        for operator in [EdgeOperator::Sobel, EdgeOperator::Scharr] {
+            let edges = edge_values(operator, 0.0, 0.0, 0.0, &step, 8, 8);
+            for (i, edge) in edges.iter().enumerate() {
+                let expected = if i % 8 == 3 || i % 8 == 4 {1.0} else {0.0};
+                assert!((edge - expected).abs() < 1e-6, "{:?} gave {} at ({}, {})", operator, edge, i % 8, i / 8);
+            }
+        }
+    }
+
+    #[test]
+    fn canny_edges_are_one_pixel_wide() {
+        let step = image(8, 8, |x, _| if x < 4 {0} else {255});
+        let edges = edge_values(EdgeOperator::Canny, 0.0, 0.1, 0.5, &step, 8, 8);
+        for row in edges.chunks(8) {
+            assert_eq!(row, [0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0]);
+        }
+    }
+
+    #[test]
+    fn canny_keeps_weak_edges_only_when_they_join_a_strong_one() {
+        // A step that is strong in the top half and weak in the bottom half, and one that is weak all the way down
+        let joined = image(12, 8, |x, y| if x < 6 {0} else if y < 4 {255} else {40});
+        let weak = image(12, 8, |x, _| if x < 6 {0} else {40});
+        let joined_edges = edge_values(EdgeOperator::Canny, 0.0, 0.1, 0.5, &joined, 12, 8);
+        let weak_edges = edge_values(EdgeOperator::Canny, 0.0, 0.1, 0.5, &weak, 12, 8);
+        // The bottom of the joined step is kept, reached from the top through the corner
+        assert!((5..8).all(|y| joined_edges[y*12 + 6] == 1.0), "{:?}", joined_edges);
+        assert!(weak_edges.iter().all(|edge| *edge == 0.0));
+    }
+}
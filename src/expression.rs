@@ -0,0 +1,536 @@
+use std::fmt;
+use image::Rgba;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use crate::rgb_to_hsv;
+
+/// A formula over a pixel's components, such as `0.3*r + 0.59*g + 0.11*b` or `abs(h - 200)`. It is parsed once and
+/// then evaluated for every pixel.
+///
+/// The variables are r, g, b and a (0-255), h (HSV hue, 0-360), s and v (HSV saturation and value, 0-100) and l (HSL
+/// lightness, 0-100). Numbers can be combined with `+ - * / ^` and brackets, and passed to abs, sqrt, floor, ceil,
+/// pow(x, y), clamp(x, low, high), min(...) and max(...).
+#[derive(Clone)]
+pub struct Expression {
+    source: String,
+    root: Node,
+    /// Whether any variable needs the pixel converted to HSV, which is skipped otherwise.
+    uses_hsv: bool,
+}
+
+/// Why an expression couldn't be parsed, and the column (counting from 1) the problem was found at.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExpressionError {
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ExpressionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at column {}", self.message, self.column)
+    }
+}
+
+impl std::error::Error for ExpressionError {}
+
+#[derive(Debug, Clone)]
+enum Node {
+    Number(f32),
+    Variable(Variable),
+    Negate(Box<Node>),
+    Binary(Operator, Box<Node>, Box<Node>),
+    Call(Function, Vec<Node>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Variable {
+    Red,
+    Green,
+    Blue,
+    Alpha,
+    Hue,
+    Saturation,
+    Value,
+    Lightness,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Operator {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Power,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Function {
+    Abs,
+    Sqrt,
+    Floor,
+    Ceil,
+    Pow,
+    Clamp,
+    Min,
+    Max,
+}
+
+impl Variable {
+    fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "r" => Variable::Red,
+            "g" => Variable::Green,
+            "b" => Variable::Blue,
+            "a" => Variable::Alpha,
+            "h" => Variable::Hue,
+            "s" => Variable::Saturation,
+            "v" => Variable::Value,
+            "l" => Variable::Lightness,
+            _ => return None,
+        })
+    }
+}
+
+impl Function {
+    fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "abs" => Function::Abs,
+            "sqrt" => Function::Sqrt,
+            "floor" => Function::Floor,
+            "ceil" => Function::Ceil,
+            "pow" => Function::Pow,
+            "clamp" => Function::Clamp,
+            "min" => Function::Min,
+            "max" => Function::Max,
+            _ => return None,
+        })
+    }
+
+    /// The fewest and most arguments the function takes.
+    fn arity(&self) -> (usize, usize) {
+        match self {
+            Function::Abs | Function::Sqrt | Function::Floor | Function::Ceil => (1, 1),
+            Function::Pow => (2, 2),
+            Function::Clamp => (3, 3),
+            Function::Min | Function::Max => (1, usize::MAX),
+        }
+    }
+}
+
+/// A pixel's components, worked out once per evaluation.
+struct Components {
+    rgba: [f32; 4],
+    hsv: (f32, f32, f32),
+    lightness: f32,
+}
+
+impl Expression {
+    pub fn parse(source: &str) -> Result<Self, ExpressionError> {
+        let mut parser = Parser { scanner: Scanner::new(source) };
+        let root = parser.expression()?;
+        parser.scanner.finish()?;
+
+        let uses_hsv = root.uses(&[Variable::Hue, Variable::Saturation, Variable::Value]);
+        Ok(Expression { source: source.to_string(), root, uses_hsv })
+    }
+
+    /// The expression as it was written.
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    pub fn evaluate(&self, pixel: &Rgba<u8>) -> f32 {
+        let components = Components {
+            rgba: pixel.0.map(|channel| channel as f32),
+            hsv: if self.uses_hsv {rgb_to_hsv(pixel)} else {(0.0, 0.0, 0.0)},
+            // HSL lightness is the mean of the largest and smallest channel
+            lightness: (pixel[0].max(pixel[1]).max(pixel[2]) as f32 + pixel[0].min(pixel[1]).min(pixel[2]) as f32)/2.0/255.0*100.0,
+        };
+        self.root.evaluate(&components)
+    }
+}
+
+impl Node {
+    fn uses(&self, variables: &[Variable]) -> bool {
+        match self {
+            Node::Number(_) => false,
+            Node::Variable(variable) => variables.contains(variable),
+            Node::Negate(node) => node.uses(variables),
+            Node::Binary(_, left, right) => left.uses(variables) || right.uses(variables),
+            Node::Call(_, arguments) => arguments.iter().any(|argument| argument.uses(variables)),
+        }
+    }
+
+    fn evaluate(&self, components: &Components) -> f32 {
+        match self {
+            Node::Number(number) => *number,
+            Node::Variable(variable) => match variable {
+                Variable::Red => components.rgba[0],
+                Variable::Green => components.rgba[1],
+                Variable::Blue => components.rgba[2],
+                Variable::Alpha => components.rgba[3],
+                Variable::Hue => components.hsv.0,
+                Variable::Saturation => components.hsv.1,
+                Variable::Value => components.hsv.2,
+                Variable::Lightness => components.lightness,
+            },
+            Node::Negate(node) => -node.evaluate(components),
+            Node::Binary(operator, left, right) => {
+                let (left, right) = (left.evaluate(components), right.evaluate(components));
+                match operator {
+                    Operator::Add => left + right,
+                    Operator::Subtract => left - right,
+                    Operator::Multiply => left*right,
+                    Operator::Divide => left/right,
+                    Operator::Power => left.powf(right),
+                }
+            },
+            Node::Call(function, arguments) => {
+                // The parser has already checked every function has enough arguments
+                let argument = |i: usize| arguments[i].evaluate(components);
+                match function {
+                    Function::Abs => argument(0).abs(),
+                    Function::Sqrt => argument(0).sqrt(),
+                    Function::Floor => argument(0).floor(),
+                    Function::Ceil => argument(0).ceil(),
+                    Function::Pow => argument(0).powf(argument(1)),
+                    Function::Clamp => argument(0).max(argument(1)).min(argument(2)),
+                    Function::Min => arguments.iter().map(|argument| argument.evaluate(components)).fold(f32::INFINITY, f32::min),
+                    Function::Max => arguments.iter().map(|argument| argument.evaluate(components)).fold(f32::NEG_INFINITY, f32::max),
+                }
+            },
+        }
+    }
+}
+
+/// How deep brackets, operators and calls can nest, which keeps parsing and evaluating from running out of stack.
+const MAX_DEPTH: usize = 128;
+
+/// Reads an expression's characters, keeping track of the column for errors and how deeply the parser has nested.
+pub(crate) struct Scanner {
+    chars: Vec<char>,
+    position: usize,
+    depth: usize,
+}
+
+impl Scanner {
+    pub(crate) fn new(source: &str) -> Self {
+        Scanner { chars: source.chars().collect(), position: 0, depth: 0 }
+    }
+
+    pub(crate) fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Goes one level deeper, failing once that is more than [`MAX_DEPTH`] levels.
+    pub(crate) fn nest(&mut self) -> Result<(), ExpressionError> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return Err(self.error(format!("Nested more than {} levels deep", MAX_DEPTH)));
+        }
+        Ok(())
+    }
+
+    /// Comes back out to an earlier depth, once what was nested inside has been read.
+    pub(crate) fn unnest(&mut self, depth: usize) {
+        self.depth = depth;
+    }
+
+    pub(crate) fn position(&self) -> usize {
+        self.position
+    }
+
+    /// Goes back to an earlier position, to undo reading something that turned out not to fit.
+    pub(crate) fn rewind(&mut self, position: usize) {
+        self.position = position;
+    }
+
+    /// An error at the current position.
+    pub(crate) fn error(&self, message: String) -> ExpressionError {
+        self.error_at(self.position, message)
+    }
+
+    /// An error at an earlier position, such as the start of a name.
+    pub(crate) fn error_at(&self, position: usize, message: String) -> ExpressionError {
+        ExpressionError { column: position + 1, message }
+    }
+
+    pub(crate) fn peek(&self) -> Option<char> {
+        self.chars.get(self.position).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().is_some_and(char::is_whitespace) {
+            self.position += 1;
+        }
+    }
+
+    /// Skips whitespace and then the text if it is next, returning whether it was.
+    pub(crate) fn eat(&mut self, text: &str) -> bool {
+        self.skip_whitespace();
+        let matches = text.chars().enumerate().all(|(i, c)| self.chars.get(self.position + i) == Some(&c));
+        if matches {
+            self.position += text.chars().count();
+        }
+        matches
+    }
+
+    /// Skips whitespace and reads a number if one is next. A dot is only part of the number when a digit follows it,
+    /// so `0.2..0.4` reads as two numbers.
+    pub(crate) fn number(&mut self) -> Option<Result<f32, ExpressionError>> {
+        self.skip_whitespace();
+        let start = self.position;
+        let is_digit = |position: usize| self.chars.get(position).is_some_and(char::is_ascii_digit);
+        if !(is_digit(start) || self.peek() == Some('.') && is_digit(start + 1)) {
+            return None;
+        }
+
+        while is_digit(self.position) {
+            self.position += 1;
+        }
+        if self.peek() == Some('.') && is_digit(self.position + 1) {
+            self.position += 1;
+            while is_digit(self.position) {
+                self.position += 1;
+            }
+        }
+        let text: String = self.chars[start..self.position].iter().collect();
+        Some(text.parse().map_err(|_| self.error_at(start, format!("'{}' is not a number", text))))
+    }
+
+    /// Skips whitespace and reads a name made of letters, digits and underscores if one is next, returning where it started.
+    pub(crate) fn word(&mut self) -> Option<(usize, String)> {
+        self.skip_whitespace();
+        let start = self.position;
+        if !self.peek().is_some_and(|c| c.is_ascii_alphabetic()) {
+            return None;
+        }
+        while self.peek().is_some_and(|c| c.is_ascii_alphanumeric() || c == '_') {
+            self.position += 1;
+        }
+        Some((start, self.chars[start..self.position].iter().collect()))
+    }
+
+    /// Checks nothing but whitespace is left.
+    pub(crate) fn finish(&mut self) -> Result<(), ExpressionError> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some(c) => Err(self.error(format!("Unexpected '{}'", c))),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Recursive descent parser, lowest precedence first: `+ -`, then `* /`, then unary minus, then `^` (which groups to
+/// the right, so `2^3^2` is `2^9` and `-2^2` is -4). Every operator, bracket and call nests a level deeper, so long
+/// chains are limited as well as deep brackets.
+struct Parser {
+    scanner: Scanner,
+}
+
+impl Parser {
+    fn expression(&mut self) -> Result<Node, ExpressionError> {
+        let depth = self.scanner.depth();
+        let mut node = self.term()?;
+        loop {
+            let operator = if self.scanner.eat("+") {
+                Operator::Add
+            } else if self.scanner.eat("-") {
+                Operator::Subtract
+            } else {
+                self.scanner.unnest(depth);
+                return Ok(node);
+            };
+            self.scanner.nest()?;
+            node = Node::Binary(operator, Box::new(node), Box::new(self.term()?));
+        }
+    }
+
+    fn term(&mut self) -> Result<Node, ExpressionError> {
+        let depth = self.scanner.depth();
+        let mut node = self.unary()?;
+        loop {
+            let operator = if self.scanner.eat("*") {
+                Operator::Multiply
+            } else if self.scanner.eat("/") {
+                Operator::Divide
+            } else {
+                self.scanner.unnest(depth);
+                return Ok(node);
+            };
+            self.scanner.nest()?;
+            node = Node::Binary(operator, Box::new(node), Box::new(self.unary()?));
+        }
+    }
+
+    fn unary(&mut self) -> Result<Node, ExpressionError> {
+        if self.scanner.eat("-") {
+            let depth = self.scanner.depth();
+            self.scanner.nest()?;
+            let node = self.unary()?;
+            self.scanner.unnest(depth);
+            Ok(Node::Negate(Box::new(node)))
+        } else {
+            self.power()
+        }
+    }
+
+    fn power(&mut self) -> Result<Node, ExpressionError> {
+        let base = self.atom()?;
+        if self.scanner.eat("^") {
+            let depth = self.scanner.depth();
+            self.scanner.nest()?;
+            let exponent = self.unary()?;
+            self.scanner.unnest(depth);
+            Ok(Node::Binary(Operator::Power, Box::new(base), Box::new(exponent)))
+        } else {
+            Ok(base)
+        }
+    }
+
+    fn atom(&mut self) -> Result<Node, ExpressionError> {
+        if self.scanner.eat("(") {
+            let depth = self.scanner.depth();
+            self.scanner.nest()?;
+            let node = self.expression()?;
+            if !self.scanner.eat(")") {
+                return Err(self.scanner.error("Expected ')'".to_string()));
+            }
+            self.scanner.unnest(depth);
+            return Ok(node);
+        }
+        if let Some(number) = self.scanner.number() {
+            return number.map(Node::Number);
+        }
+        if let Some((start, name)) = self.scanner.word() {
+            if self.scanner.eat("(") {
+                let function = Function::from_name(&name).ok_or_else(|| self.scanner.error_at(start, format!("Unknown function '{}' (possible functions: abs, sqrt, floor, ceil, pow, clamp, min, max)", name)))?;
+                let depth = self.scanner.depth();
+                self.scanner.nest()?;
+                let arguments = self.arguments()?;
+                self.scanner.unnest(depth);
+                let (fewest, most) = function.arity();
+                if arguments.len() < fewest || arguments.len() > most {
+                    let expected = if fewest == most {fewest.to_string()} else {format!("at least {}", fewest)};
+                    return Err(self.scanner.error_at(start, format!("'{}' takes {} argument(s) but was given {}", name, expected, arguments.len())));
+                }
+                return Ok(Node::Call(function, arguments));
+            }
+            return Variable::from_name(&name).map(Node::Variable).ok_or_else(|| self.scanner.error_at(start, format!("Unknown variable '{}' (possible variables: r, g, b, a, h, s, v, l)", name)));
+        }
+        match self.scanner.peek() {
+            Some(c) => Err(self.scanner.error(format!("Expected a number, variable or '(' but found '{}'", c))),
+            None => Err(self.scanner.error("Expected a number, variable or '(' but the expression ended".to_string())),
+        }
+    }
+
+    /// Parses a comma separated argument list, after its opening bracket.
+    fn arguments(&mut self) -> Result<Vec<Node>, ExpressionError> {
+        let mut arguments = Vec::new();
+        if self.scanner.eat(")") {
+            return Ok(arguments);
+        }
+        loop {
+            arguments.push(self.expression()?);
+            if self.scanner.eat(")") {
+                return Ok(arguments);
+            }
+            if !self.scanner.eat(",") {
+                return Err(self.scanner.error("Expected ',' or ')'".to_string()));
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.source)
+    }
+}
+
+/// Expressions are equal when they were written the same way.
+impl PartialEq for Expression {
+    fn eq(&self, other: &Self) -> bool {
+        self.source == other.source
+    }
+}
+
+impl Eq for Expression {}
+
+impl Serialize for Expression {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.source)
+    }
+}
+
+impl<'de> Deserialize<'de> for Expression {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let source = String::deserialize(deserializer)?;
+        Expression::parse(&source).map_err(|err| serde::de::Error::custom(format!("The expression \"{}\" is not valid: {}.", source, err)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgba;
+    use super::{Expression, ExpressionError};
+
+    fn evaluate(source: &str) -> f32 {
+        Expression::parse(source).unwrap().evaluate(&Rgba([10, 20, 30, 255]))
+    }
+
+    fn error(source: &str) -> ExpressionError {
+        Expression::parse(source).err().unwrap_or_else(|| panic!("'{}' parsed", source))
+    }
+
+    #[test]
+    fn operators_follow_their_precedence() {
+        assert_eq!(evaluate("2^3^2"), 512.0);
+        assert_eq!(evaluate("-2^2"), -4.0);
+        assert_eq!(evaluate("2^-1"), 0.5);
+        assert_eq!(evaluate("1 + 2*3"), 7.0);
+        assert_eq!(evaluate("(1 + 2)*3"), 9.0);
+        assert_eq!(evaluate("8 - 2 - 1"), 5.0);
+        assert_eq!(evaluate("8/2/2"), 2.0);
+        assert_eq!(evaluate("r + g*b"), 610.0);
+        assert_eq!(evaluate("max(r, g, b) - min(r, g, b)"), 20.0);
+        assert_eq!(evaluate("clamp(b, 0, 25)"), 25.0);
+    }
+
+    #[test]
+    fn functions_must_be_given_the_right_number_of_arguments() {
+        assert_eq!(error("pow(r)"), ExpressionError { column: 1, message: "'pow' takes 2 argument(s) but was given 1".to_string() });
+        assert_eq!(error("g + clamp(r, 0, 1, 2)"), ExpressionError { column: 5, message: "'clamp' takes 3 argument(s) but was given 4".to_string() });
+        assert_eq!(error("abs()"), ExpressionError { column: 1, message: "'abs' takes 1 argument(s) but was given 0".to_string() });
+        assert_eq!(error("max()"), ExpressionError { column: 1, message: "'max' takes at least 1 argument(s) but was given 0".to_string() });
+    }
+
+    #[test]
+    fn unknown_names_are_reported_where_they_start() {
+        let unknown_variable = error("r + x");
+        assert_eq!(unknown_variable.column, 5);
+        assert!(unknown_variable.message.starts_with("Unknown variable 'x'"));
+        let unknown_function = error("2*log(r)");
+        assert_eq!(unknown_function.column, 3);
+        assert!(unknown_function.message.starts_with("Unknown function 'log'"));
+        // Names are case sensitive
+        assert_eq!(error("R").column, 1);
+    }
+
+    #[test]
+    fn syntax_errors_point_at_the_right_column() {
+        assert_eq!(error("0.3*r + "), ExpressionError { column: 9, message: "Expected a number, variable or '(' but the expression ended".to_string() });
+        assert_eq!(error("max(r,, g)"), ExpressionError { column: 7, message: "Expected a number, variable or '(' but found ','".to_string() });
+        assert_eq!(error("(r + g"), ExpressionError { column: 7, message: "Expected ')'".to_string() });
+        assert_eq!(error("r g"), ExpressionError { column: 3, message: "Unexpected 'g'".to_string() });
+    }
+
+    #[test]
+    fn nesting_too_deeply_is_an_error() {
+        assert_eq!(evaluate(&format!("{}r{}", "(".repeat(100), ")".repeat(100))), 10.0);
+        // The error is just after the bracket that goes one level too deep
+        let err = error(&format!("{}r{}", "(".repeat(100_000), ")".repeat(100_000)));
+        assert_eq!(err, ExpressionError { column: 130, message: "Nested more than 128 levels deep".to_string() });
+        assert_eq!(error(&"-".repeat(100_000)).column, 130);
+        assert_eq!(error(&vec!["r"; 100_000].join(" + ")).message, "Nested more than 128 levels deep");
+    }
+}
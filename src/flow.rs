@@ -0,0 +1,147 @@
+use std::f64::consts::TAU;
+use image::RgbaImage;
+use crate::random::SplitMix64;
+use crate::traversal::GroupedLines;
+
+/// A direction at every pixel, as a unit vector in image coordinates (y down), or `None` where the field is still.
+pub trait FlowField {
+    fn direction(&self, x: usize, y: usize) -> Option<(f64, f64)>;
+}
+
+/// A flow map image, stretched over the image being sorted. Red is the rightwards part of the direction and green
+/// the upwards part, with 128 meaning none, so (255, 128) flows right and (128, 255) flows up.
+pub struct FlowMap<'a> {
+    pub map: &'a RgbaImage,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl FlowField for FlowMap<'_> {
+    fn direction(&self, x: usize, y: usize) -> Option<(f64, f64)> {
+        let map_x = (x*self.map.width() as usize/self.width) as u32;
+        let map_y = (y*self.map.height() as usize/self.height) as u32;
+        let pixel = self.map.get_pixel(map_x, map_y);
+        let (dx, dy) = ((pixel[0] as f64 - 128.0)/127.0, (128.0 - pixel[1] as f64)/127.0);
+        let length = dx.hypot(dy);
+        // A flow map can't quite encode 0, so treat anything within a step of 128 as still
+        if length < 1.0/127.0 {
+            None
+        } else {
+            Some((dx/length, dy/length))
+        }
+    }
+}
+
+/// Perlin noise turned into directions, with features roughly `scale` pixels across.
+pub struct NoiseField {
+    scale: f64,
+    permutation: [u8; 512],
+}
+
+impl NoiseField {
+    pub fn new(seed: u64, scale: f64) -> Self {
+        let mut permutation = [0u8; 512];
+        let mut table: Vec<u8> = (0..=255).collect();
+        let mut random = SplitMix64::new(seed);
+        for i in (1..table.len()).rev() {
+            table.swap(i, (random.next_u64() % (i as u64 + 1)) as usize);
+        }
+        for i in 0..512 {
+            permutation[i] = table[i % 256];
+        }
+        NoiseField { scale, permutation }
+    }
+
+    /// Classic 2D Perlin noise, roughly -1.0 to 1.0.
+    fn noise(&self, x: f64, y: f64) -> f64 {
+        let (cell_x, cell_y) = (x.floor(), y.floor());
+        let (fx, fy) = (x - cell_x, y - cell_y);
+        let (ix, iy) = ((cell_x as i64 & 255) as usize, (cell_y as i64 & 255) as usize);
+
+        let gradient = |corner_x: usize, corner_y: usize, dx: f64, dy: f64| {
+            match self.permutation[self.permutation[ix + corner_x] as usize + iy + corner_y] & 3 {
+                0 => dx + dy,
+                1 => -dx + dy,
+                2 => dx - dy,
+                _ => -dx - dy,
+            }
+        };
+        let fade = |t: f64| t*t*t*(t*(t*6.0 - 15.0) + 10.0);
+        let lerp = |a: f64, b: f64, t: f64| a + t*(b - a);
+
+        let (u, v) = (fade(fx), fade(fy));
+        lerp(
+            lerp(gradient(0, 0, fx, fy), gradient(1, 0, fx - 1.0, fy), u),
+            lerp(gradient(0, 1, fx, fy - 1.0), gradient(1, 1, fx - 1.0, fy - 1.0), u),
+            v,
+        )
+    }
+}
+
+impl FlowField for NoiseField {
+    fn direction(&self, x: usize, y: usize) -> Option<(f64, f64)> {
+        // Two full turns across the noise's range, so the field curls rather than just bending
+        let angle = self.noise(x as f64/self.scale, y as f64/self.scale)*2.0*TAU;
+        Some((angle.cos(), angle.sin()))
+    }
+}
+
+/// Most steps needed to leave a pixel, since a step can land back in the same pixel.
+const MAX_STEPS_PER_PIXEL: usize = 4;
+
+/// Traces lines through the field. Starting from each pixel not yet on a line (in reading order), a line is traced
+/// backwards against the flow and then forwards with it, stopping at the edge, at still pixels or at pixels already
+/// on a line. Every pixel ends up on exactly one line, and the same field always gives the same lines.
+pub fn trace(field: &dyn FlowField, width: usize, height: usize) -> GroupedLines {
+    let mut visited = vec![false; width*height];
+    let mut starts = vec![0];
+    let mut indices = Vec::with_capacity(width*height);
+    let mut backwards = Vec::new();
+    let mut forwards = Vec::new();
+
+    for start in 0..width*height {
+        if visited[start] {
+            continue;
+        }
+        visited[start] = true;
+        follow(field, width, height, start, -1.0, &mut visited, &mut backwards);
+        follow(field, width, height, start, 1.0, &mut visited, &mut forwards);
+
+        indices.extend(backwards.iter().rev());
+        indices.push(start);
+        indices.extend(forwards.iter());
+        starts.push(indices.len());
+    }
+    GroupedLines::new(starts, indices)
+}
+
+/// Follows the field (against it when `sign` is -1) from a pixel, marking and collecting the pixels it passes.
+fn follow(field: &dyn FlowField, width: usize, height: usize, start: usize, sign: f64, visited: &mut [bool], path: &mut Vec<usize>) {
+    path.clear();
+    let mut current = start;
+    let (mut x, mut y) = ((start % width) as f64 + 0.5, (start / width) as f64 + 0.5);
+
+    'path: loop {
+        let Some((dx, dy)) = field.direction(current % width, current / width) else {
+            return;
+        };
+        for _ in 0..MAX_STEPS_PER_PIXEL {
+            x += sign*dx;
+            y += sign*dy;
+            if x < 0.0 || y < 0.0 || x >= width as f64 || y >= height as f64 {
+                return;
+            }
+            let next = y as usize*width + x as usize;
+            if next != current {
+                if visited[next] {
+                    return;
+                }
+                visited[next] = true;
+                path.push(next);
+                current = next;
+                continue 'path;
+            }
+        }
+        return;
+    }
+}
@@ -0,0 +1,43 @@
+use image::RgbaImage;
+use crate::{contrast_value, ContrastType};
+
+/// Summary of one contrast channel over an image, on the same 0.0-1.0 scale as the contrast map bounds.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChannelStats {
+    pub min: f32,
+    pub max: f32,
+    pub mean: f32,
+    pub std_dev: f32,
+    pub median: f32,
+}
+
+/// Computes [`ChannelStats`] for every [`ContrastType`], to help pick contrast map bounds.
+pub fn channel_stats(img: &RgbaImage) -> Vec<(ContrastType, ChannelStats)> {
+    let contrast_types = [
+        ContrastType::Red, ContrastType::Green, ContrastType::Blue, ContrastType::Hue, ContrastType::Saturation, ContrastType::Value,
+        ContrastType::Luminance, ContrastType::Lightness, ContrastType::LabLightness, ContrastType::LchChroma, ContrastType::LchHue,
+        ContrastType::OklabLightness, ContrastType::OklabChroma, ContrastType::OklabHue,
+    ];
+    contrast_types.into_iter().map(|contrast_type| {
+        let mut values: Vec<f32> = img.pixels().map(|pixel| contrast_value(&contrast_type, pixel)).collect();
+        (contrast_type, stats_of(&mut values))
+    }).collect()
+}
+
+fn stats_of(values: &mut [f32]) -> ChannelStats {
+    if values.is_empty() {
+        return ChannelStats { min: 0.0, max: 0.0, mean: 0.0, std_dev: 0.0, median: 0.0 };
+    }
+    values.sort_unstable_by(f32::total_cmp);
+
+    let count = values.len() as f64;
+    let mean = values.iter().map(|value| *value as f64).sum::<f64>() / count;
+    let variance = values.iter().map(|value| (*value as f64 - mean).powi(2)).sum::<f64>() / count;
+    ChannelStats {
+        min: values[0],
+        max: values[values.len() - 1],
+        mean: mean as f32,
+        std_dev: variance.sqrt() as f32,
+        median: values[values.len() / 2],
+    }
+}
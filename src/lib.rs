@@ -0,0 +1,745 @@
+//! Pixel sorting engine. Build a [`PixelSorter`] (directly or from [`ProgramSettings`]) and hand it an in-memory image;
+//! the sorted image and the contrast map used to sort it are returned without touching the filesystem.
+
+mod colour;
+mod config;
+mod contrast;
+mod edges;
+mod expression;
+mod flow;
+mod inspect;
+mod mask;
+mod predicate;
+mod random;
+mod sort;
+mod spans;
+mod traversal;
+
+use std::fmt;
+use std::sync::{Arc, OnceLock};
+use image::{DynamicImage, Rgba, RgbaImage};
+use serde::{Deserialize, Serialize};
+use contrast::map_contrast_values;
+use mask::apply_mask;
+
+pub use colour::{colour_distance, luminance, rgb_to_hsl, rgb_to_hsv, rgb_to_lab, rgb_to_lch, rgb_to_oklab, rgb_to_oklch, Colour};
+pub use config::{ConfigError, ConfigFormat, DEFAULT_CONFIG};
+pub use contrast::{contrast_value, contrast_values, create_contrast_map};
+pub use expression::{Expression, ExpressionError};
+pub use inspect::{channel_stats, ChannelStats};
+pub use predicate::Predicate;
+pub use sort::sort_pixels;
+
+/// Everything the front end reads from the settings file. Missing keys take their values from [`Default`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProgramSettings {
+    pub input_path: String,
+    pub sort_direction: SortDirection,
+    pub sort_order: SortOrder,
+    pub sort_by: SortBy,
+    /// Keys that order pixels whose earlier keys are equal, in turn.
+    pub tie_breakers: Vec<TieBreaker>,
+    /// Keep pixels with exactly equal keys in their original order.
+    pub stable_sort: bool,
+    /// The hue (in degrees) hue keys start from, so hues just below it sort last instead of being split across both ends.
+    pub hue_origin: f32,
+    pub contrast_map_lower: f32,
+    pub contrast_map_upper: f32,
+    /// Picks the lower bound for each image instead of using `contrast_map_lower`.
+    pub auto_threshold: AutoThreshold,
+    /// More (lower, upper) ranges a contrast value can lie in, on top of the bounds above.
+    pub contrast_map_ranges: Vec<(f32, f32)>,
+    /// Sort the pixels outside the ranges instead of those inside them.
+    pub invert_contrast_map: bool,
+    pub contrast_type: ContrastType,
+    /// A mask that replaces or is combined with the contrast map.
+    pub mask: MaskSource,
+    /// The grey level (or alpha) from 0.0 to 1.0 a mask pixel must reach to be in the mask.
+    pub mask_threshold: f32,
+    /// How the mask and the contrast map are combined.
+    pub mask_combine: MaskCombine,
+    /// What to do when a mask image isn't the same size as the image being sorted.
+    pub mask_resize: MaskResize,
+    /// How spans are found along each line.
+    pub span_mode: SpanMode,
+    /// Spans shorter than this many pixels are left unsorted.
+    pub min_span_length: usize,
+    /// Spans are cut into pieces at most this many pixels long, 0 for no limit.
+    pub max_span_length: usize,
+    /// Cuts spans into pieces of random length.
+    pub span_split: SpanSplit,
+    pub should_debug: bool,
+    /// How many threads to sort with, 0 for one per core.
+    pub threads: usize,
+    /// Seeds every random choice, so a run can be repeated exactly.
+    pub seed: u64,
+}
+
+/// The direction keys increase in. Horizontal and vertical are the same as right and down.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortDirection {
+    Left,
+    Right,
+    Up,
+    Down,
+    Horizontal,
+    Vertical,
+    /// Parallel lines at an angle in degrees, counterclockwise from right (0 is right, 90 is up).
+    Angle(f32),
+    /// Rays out from the centre, increasing outwards.
+    Radial {
+        #[serde(default)]
+        centre: CentrePoint,
+    },
+    /// Rings around the centre, increasing counterclockwise from the right of each ring.
+    Concentric {
+        #[serde(default)]
+        centre: CentrePoint,
+    },
+    /// One Archimedean spiral out from the centre, with its turns `spacing` pixels apart.
+    Spiral {
+        #[serde(default)]
+        centre: CentrePoint,
+        #[serde(default = "default_spiral_spacing")]
+        spacing: f32,
+    },
+    /// Curved lines following a flow field, increasing along the flow.
+    Flow(FlowSource),
+    /// Sine waves across the image, each walked left to right (or top to bottom when vertical). The amplitude and
+    /// wavelength are in pixels and the phase is in degrees.
+    Wave {
+        #[serde(default)]
+        axis: WaveAxis,
+        #[serde(default = "default_wave_amplitude")]
+        amplitude: f32,
+        #[serde(default = "default_wave_wavelength")]
+        wavelength: f32,
+        #[serde(default)]
+        phase: f32,
+    },
+    /// The whole image as one path along the rows, left to right then back right to left, so spans carry on into the next row.
+    Serpentine,
+    /// The whole image as one Hilbert curve, so spans wander through neighbouring blocks.
+    Hilbert,
+    /// The whole image as one Morton (Z-order) curve, visiting each square block before the next.
+    ZOrder,
+}
+
+/// Where a [`SortDirection::Flow`] field comes from.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FlowSource {
+    /// A flow map image, where red is the rightwards and green the upwards part of the direction (128 is none).
+    /// The path is only recorded here; the image itself is handed to [`PixelSorter::flow_map`].
+    Map(String),
+    /// Perlin noise seeded by the seed setting, with features roughly `scale` pixels across.
+    Noise {
+        #[serde(default = "default_noise_scale")]
+        scale: f32,
+    },
+}
+
+/// Which way a [`SortDirection::Wave`] runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WaveAxis {
+    #[default]
+    Horizontal,
+    Vertical,
+}
+
+fn default_wave_amplitude() -> f32 {
+    10.0
+}
+
+fn default_wave_wavelength() -> f32 {
+    100.0
+}
+
+fn default_noise_scale() -> f32 {
+    100.0
+}
+
+fn default_spiral_spacing() -> f32 {
+    1.0
+}
+
+/// A point in the image, either in pixels from the top left or as a fraction of the image size.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CentrePoint {
+    Pixels(f32, f32),
+    Fraction(f32, f32),
+}
+
+impl Default for CentrePoint {
+    fn default() -> Self {
+        CentrePoint::Fraction(0.5, 0.5)
+    }
+}
+
+impl CentrePoint {
+    /// The point in pixel coordinates, where a fraction of 1.0 is the last pixel.
+    pub fn resolve(&self, width: usize, height: usize) -> (f64, f64) {
+        match *self {
+            CentrePoint::Pixels(x, y) => (x as f64, y as f64),
+            CentrePoint::Fraction(x, y) => (x as f64*(width.max(1) - 1) as f64, y as f64*(height.max(1) - 1) as f64),
+        }
+    }
+}
+
+/// How each span is ordered along the sort direction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    /// Keys increase towards the sort direction.
+    Ascending,
+    /// Keys decrease towards the sort direction.
+    Descending,
+    /// Ascending on even lines and descending on odd lines.
+    Alternating,
+    /// Ascending or descending, picked per span from the seed.
+    Random,
+    /// Ascending towards the middle of each span and descending after it, so the highest key sits in the middle.
+    Mirror,
+}
+
+/// What the pixels in a span are ordered by.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SortBy {
+    Red,
+    Green,
+    Blue,
+    /// HSV hue.
+    Hue,
+    /// HSV saturation.
+    Saturation,
+    /// HSV value, the largest channel.
+    Value,
+    /// Rec. 709 relative luminance.
+    Luminance,
+    /// HSL lightness, halfway between the largest and smallest channel.
+    Lightness,
+    /// CIE L*a*b* lightness (L*).
+    LabLightness,
+    /// CIE LCh chroma.
+    LchChroma,
+    /// CIE LCh hue.
+    LchHue,
+    /// Oklab lightness.
+    OklabLightness,
+    /// Oklab chroma.
+    OklabChroma,
+    /// Oklab hue.
+    OklabHue,
+    /// A formula over the pixel's components, e.g. `{ expression = "max(r, g, b) - min(r, g, b)" }`.
+    Expression(Expression),
+    /// How far the pixel is from a colour, e.g. `{ distance_to = { colour = "#008080", space = "lab" } }`.
+    DistanceTo {
+        colour: Colour,
+        #[serde(default)]
+        space: DistanceSpace,
+    },
+}
+
+/// What the contrast map is made from. Every value but an expression's is scaled to 0.0-1.0 before it is compared with
+/// the bounds.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ContrastType {
+    Red,
+    Green,
+    Blue,
+    /// HSV hue.
+    Hue,
+    /// HSV saturation.
+    Saturation,
+    /// HSV value, the largest channel.
+    Value,
+    /// Rec. 709 relative luminance.
+    Luminance,
+    /// HSL lightness, halfway between the largest and smallest channel.
+    Lightness,
+    /// CIE L*a*b* lightness (L*).
+    LabLightness,
+    /// CIE LCh chroma.
+    LchChroma,
+    /// CIE LCh hue.
+    LchHue,
+    /// Oklab lightness.
+    OklabLightness,
+    /// Oklab chroma.
+    OklabChroma,
+    /// Oklab hue.
+    OklabHue,
+    /// A formula over the pixel's components, e.g. `{ expression = "max(r, g, b) - min(r, g, b)" }`.
+    Expression(Expression),
+    /// How far the pixel is from a colour, e.g. `{ distance_to = { colour = "#008080", space = "lab" } }`.
+    DistanceTo {
+        colour: Colour,
+        #[serde(default)]
+        space: DistanceSpace,
+    },
+    /// How strong the edges in the image are at each pixel, e.g. `{ edges = { operator = "canny" } }`. The image is
+    /// blurred by a Gaussian with a standard deviation of `blur` pixels first, and `low` and `high` are the Canny
+    /// hysteresis thresholds.
+    Edges {
+        #[serde(default)]
+        operator: EdgeOperator,
+        #[serde(default = "default_edge_blur")]
+        blur: f32,
+        #[serde(default = "default_edge_low")]
+        low: f32,
+        #[serde(default = "default_edge_high")]
+        high: f32,
+    },
+    /// A test combining several contrast values, e.g. `{ predicate = "value > 0.4 and saturation < 0.2" }`. The bounds
+    /// and ranges are ignored, as the predicate already decides which pixels are sorted.
+    Predicate(Predicate),
+}
+
+/// The colour space a [`SortBy::DistanceTo`] distance is measured in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DistanceSpace {
+    /// Straight line distance between the channels.
+    Rgb,
+    /// CIEDE2000 difference, which follows how different colours look most closely.
+    #[default]
+    Lab,
+    /// Straight line distance in Oklab, nearly as even as CIEDE2000 and faster.
+    Oklab,
+}
+
+/// How [`ContrastType::Edges`] finds edges.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EdgeOperator {
+    /// Sobel gradient magnitude.
+    #[default]
+    Sobel,
+    /// Scharr gradient magnitude, which treats diagonal edges more evenly than Sobel.
+    Scharr,
+    /// Canny edges: Sobel gradients thinned to one pixel wide lines, kept when they are at least `high` strong or
+    /// connect to such an edge while at least `low` strong.
+    Canny,
+}
+
+fn default_edge_blur() -> f32 {
+    1.0
+}
+
+fn default_edge_low() -> f32 {
+    0.05
+}
+
+fn default_edge_high() -> f32 {
+    0.1
+}
+
+/// How the contrast map lower bound is picked for each image.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AutoThreshold {
+    /// Use the lower bound as it is set.
+    #[default]
+    Off,
+    /// Otsu's method, which splits the contrast values where the two sides are each as even as they can be.
+    Otsu,
+    /// The lower bound that sorts this fraction (0.0-1.0) of the pixels, e.g. `{ coverage = 0.35 }`, whether or not the
+    /// contrast map is inverted.
+    Coverage(f32),
+}
+
+/// How the spans that get sorted are found along each line.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SpanMode {
+    /// Every run of pixels in the contrast map is a span.
+    #[default]
+    Mask,
+    /// A span starts at a pixel in the contrast map whose contrast value is at least `high`, and carries on while the
+    /// pixels stay in the contrast map, e.g. `{ hysteresis = { high = 0.8 } }`. The bounds act as the low threshold,
+    /// so noise dipping in and out of them doesn't start lots of tiny spans.
+    Hysteresis {
+        high: f32,
+    },
+    /// Runs of pixels in the contrast map, cut wherever the contrast value changes by more than `delta` from one pixel
+    /// to the next, e.g. `{ difference = { delta = 0.1 } }`. This sorts until the colour jumps.
+    Difference {
+        delta: f32,
+    },
+}
+
+/// Where the mask combined with the contrast map comes from.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MaskSource {
+    /// No mask, the contrast map is used as it is.
+    #[default]
+    Off,
+    /// A black and white or greyscale mask image, e.g. `{ image = "mask.png" }`. The path is only recorded here; the
+    /// image itself is handed to [`PixelSorter::mask_image`].
+    Image(String),
+    /// The alpha channel of the image being sorted.
+    Alpha,
+}
+
+/// How a mask is combined with the contrast map.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MaskCombine {
+    /// Only the mask decides which pixels are sorted.
+    #[default]
+    Replace,
+    /// Pixels must be in both the mask and the contrast map.
+    And,
+    /// Pixels can be in either the mask or the contrast map.
+    Or,
+}
+
+/// What to do when a mask image isn't the same size as the image being sorted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MaskResize {
+    /// Refuse to sort the image.
+    #[default]
+    Error,
+    /// Stretch the mask over the image.
+    Stretch,
+}
+
+/// How spans are cut into pieces of random length, seeded by the seed setting.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SpanSplit {
+    /// Leave spans whole.
+    #[default]
+    Off,
+    /// Pieces between `min` and `max` pixels long, every length equally likely, e.g. `{ uniform = { min = 20, max = 80 } }`.
+    Uniform {
+        min: usize,
+        max: usize,
+    },
+    /// Pieces `mean` pixels long on average, with short pieces more likely than long ones, e.g. `{ exponential = { mean = 50.0 } }`.
+    Exponential {
+        mean: f32,
+    },
+}
+
+/// A key that orders pixels whose earlier keys are equal.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TieBreaker {
+    pub key: SortBy,
+    #[serde(default)]
+    pub order: KeyOrder,
+}
+
+/// Which way a [`TieBreaker`] orders pixels, relative to the sort order of the span.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyOrder {
+    #[default]
+    Ascending,
+    Descending,
+}
+
+/// Builder holding everything needed to sort an image: the settings, along with any images they point to. The defaults
+/// match the default settings file.
+#[derive(Debug, Clone, Default)]
+pub struct PixelSorter {
+    settings: ProgramSettings,
+    flow_map: Option<RgbaImage>,
+    mask_image: Option<RgbaImage>,
+    /// The threads sorting runs on when `threads` isn't 0, started the first time they are needed.
+    thread_pool: OnceLock<Arc<rayon::ThreadPool>>,
+}
+
+impl From<&ProgramSettings> for PixelSorter {
+    fn from(program_settings: &ProgramSettings) -> Self {
+        PixelSorter { settings: program_settings.clone(), ..PixelSorter::default() }
+    }
+}
+
+impl PixelSorter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn sort_direction(mut self, sort_direction: SortDirection) -> Self {
+        self.settings.sort_direction = sort_direction;
+        self
+    }
+
+    pub fn sort_order(mut self, sort_order: SortOrder) -> Self {
+        self.settings.sort_order = sort_order;
+        self
+    }
+
+    pub fn sort_by(mut self, sort_by: SortBy) -> Self {
+        self.settings.sort_by = sort_by;
+        self
+    }
+
+    /// Sets the keys that order pixels whose earlier keys are equal, in the order they are compared.
+    pub fn tie_breakers(mut self, tie_breakers: Vec<TieBreaker>) -> Self {
+        self.settings.tie_breakers = tie_breakers;
+        self
+    }
+
+    /// Keeps pixels with exactly equal keys in their original order, rather than in whatever order is fastest.
+    pub fn stable_sort(mut self, stable_sort: bool) -> Self {
+        self.settings.stable_sort = stable_sort;
+        self
+    }
+
+    /// Rotates hue keys so they start from this hue in degrees rather than from red at 0.
+    pub fn hue_origin(mut self, hue_origin: f32) -> Self {
+        self.settings.hue_origin = hue_origin;
+        self
+    }
+
+    /// Sets the bounds (0.0-1.0, or any numbers for an expression) a pixel's contrast value must lie between for it to
+    /// be sorted. When the lower bound is above the upper one, values outside the band are sorted instead.
+    pub fn contrast_bounds(mut self, lower: f32, upper: f32) -> Self {
+        self.settings.contrast_map_lower = lower;
+        self.settings.contrast_map_upper = upper;
+        self
+    }
+
+    /// Picks the lower bound for each image from its contrast values, keeping the upper bound as it is.
+    pub fn auto_threshold(mut self, auto_threshold: AutoThreshold) -> Self {
+        self.settings.auto_threshold = auto_threshold;
+        self
+    }
+
+    /// Sets more (lower, upper) ranges a pixel's contrast value can lie in to be sorted, on top of the bounds.
+    pub fn contrast_ranges(mut self, ranges: Vec<(f32, f32)>) -> Self {
+        self.settings.contrast_map_ranges = ranges;
+        self
+    }
+
+    /// Sorts the pixels outside the contrast ranges instead of those inside them.
+    pub fn invert_contrast_map(mut self, invert: bool) -> Self {
+        self.settings.invert_contrast_map = invert;
+        self
+    }
+
+    pub fn contrast_type(mut self, contrast_type: ContrastType) -> Self {
+        self.settings.contrast_type = contrast_type;
+        self
+    }
+
+    /// Sets where the mask combined with the contrast map comes from, and how it is combined.
+    pub fn mask(mut self, mask: MaskSource, combine: MaskCombine) -> Self {
+        self.settings.mask = mask;
+        self.settings.mask_combine = combine;
+        self
+    }
+
+    /// Sets the grey level (or alpha) from 0.0 to 1.0 a mask pixel must reach to be in the mask.
+    pub fn mask_threshold(mut self, threshold: f32) -> Self {
+        self.settings.mask_threshold = threshold;
+        self
+    }
+
+    /// Sets what to do when the mask image isn't the same size as the image being sorted.
+    pub fn mask_resize(mut self, resize: MaskResize) -> Self {
+        self.settings.mask_resize = resize;
+        self
+    }
+
+    /// Sets the mask image read by [`MaskSource::Image`].
+    pub fn mask_image(mut self, mask_image: RgbaImage) -> Self {
+        self.mask_image = Some(mask_image);
+        self
+    }
+
+    /// Sets how the spans that get sorted are found along each line.
+    pub fn span_mode(mut self, span_mode: SpanMode) -> Self {
+        self.settings.span_mode = span_mode;
+        self
+    }
+
+    /// Leaves spans shorter than `min` pixels unsorted and cuts spans into pieces at most `max` pixels long (0 for no
+    /// limit).
+    pub fn span_lengths(mut self, min: usize, max: usize) -> Self {
+        self.settings.min_span_length = min;
+        self.settings.max_span_length = max;
+        self
+    }
+
+    /// Cuts spans into pieces of random length, seeded by [`PixelSorter::seed`].
+    pub fn span_split(mut self, span_split: SpanSplit) -> Self {
+        self.settings.span_split = span_split;
+        self
+    }
+
+    /// Prints progress while sorting.
+    pub fn debug(mut self, should_debug: bool) -> Self {
+        self.settings.should_debug = should_debug;
+        self
+    }
+
+    /// Builds only the contrast map for an image, without sorting it. The mask is combined with it, which fails when
+    /// the mask image is missing or the wrong size.
+    pub fn contrast_map(&self, img: &RgbaImage) -> Result<Vec<bool>, SortError> {
+        self.contrast_map_with_bounds(img).map(|(contrast_map, _)| contrast_map)
+    }
+
+    /// Builds only the contrast map for an image, along with the bounds it was built with (which differ from the set
+    /// ones when [`AutoThreshold`] picks them).
+    pub fn contrast_map_with_bounds(&self, img: &RgbaImage) -> Result<(Vec<bool>, (f32, f32)), SortError> {
+        self.settings.validate().map_err(|err| SortError::InvalidSettings(err.to_string()))?;
+        self.on_threads(|| {
+            let (mut contrast_map, contrast_bounds) = map_contrast_values(self, &self.contrast_values(img));
+            apply_mask(self, img, &mut contrast_map)?;
+            Ok((contrast_map, contrast_bounds))
+        })?
+    }
+
+    /// The contrast value of every pixel in an image, which the contrast map compares with the bounds. Render it
+    /// with [`contrast_values_image`], e.g. to see the edges found by [`ContrastType::Edges`].
+    pub fn contrast_values(&self, img: &RgbaImage) -> Vec<f32> {
+        let pixels_vec: Vec<Rgba<u8>> = img.pixels().copied().collect();
+        contrast_values(&self.settings.contrast_type, &pixels_vec, img.width() as usize, img.height() as usize)
+    }
+
+    /// Sets how many threads rows and columns are sorted on, 0 (the default) for one per core.
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.settings.threads = threads;
+        self.thread_pool = OnceLock::new();
+        self
+    }
+
+    /// Runs the work on the sorter's threads, or on rayon's global pool when `threads` is 0.
+    pub(crate) fn on_threads<T: Send>(&self, work: impl FnOnce() -> T + Send) -> Result<T, SortError> {
+        if self.settings.threads == 0 {
+            return Ok(work());
+        }
+        let thread_pool = match self.thread_pool.get() {
+            Some(thread_pool) => thread_pool,
+            None => {
+                let built = rayon::ThreadPoolBuilder::new().num_threads(self.settings.threads).build().map_err(|err| SortError::ThreadPool(err.to_string()))?;
+                self.thread_pool.get_or_init(|| Arc::new(built))
+            }
+        };
+        Ok(thread_pool.install(work))
+    }
+
+    /// Seeds the random choices, such as [`SortOrder::Random`].
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.settings.seed = seed;
+        self
+    }
+
+    /// Sets the flow map image read by [`FlowSource::Map`]. It is stretched to fit each image being sorted.
+    pub fn flow_map(mut self, flow_map: RgbaImage) -> Self {
+        self.flow_map = Some(flow_map);
+        self
+    }
+
+    pub fn sort_image(&self, img: &DynamicImage) -> Result<SortOutput, SortError> {
+        self.sort_rgba(&img.to_rgba8())
+    }
+
+    pub fn sort_rgba(&self, img: &RgbaImage) -> Result<SortOutput, SortError> {
+        self.settings.validate().map_err(|err| SortError::InvalidSettings(err.to_string()))?;
+        // Finding edges is parallel as well, so the whole sort runs on the sorter's threads
+        self.on_threads(|| {
+            let (width, height) = img.dimensions();
+            let mut pixels_vec: Vec<Rgba<u8>> = img.pixels().copied().collect();
+
+            let contrast_values = contrast_values(&self.settings.contrast_type, &pixels_vec, width as usize, height as usize);
+            let (mut contrast_map, contrast_bounds) = map_contrast_values(self, &contrast_values);
+            apply_mask(self, img, &mut contrast_map)?;
+            sort_pixels(self, &mut pixels_vec, &contrast_map, &contrast_values, width as usize, height as usize)?;
+
+            let image = RgbaImage::from_fn(width, height, |x, y| pixels_vec[y as usize*width as usize + x as usize]);
+            Ok(SortOutput { image, contrast_map, contrast_values, contrast_bounds })
+        })?
+    }
+}
+
+/// Why an image couldn't be sorted.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SortError {
+    /// The settings don't pass [`ProgramSettings::validate`].
+    InvalidSettings(String),
+    /// The sort direction follows a flow map, but no image was given to [`PixelSorter::flow_map`].
+    MissingFlowMap(String),
+    /// The flow map image has no pixels.
+    EmptyFlowMap(String),
+    /// The pixels, contrast map or contrast values given to [`sort_pixels`] don't have one entry per pixel.
+    WrongLength {
+        name: &'static str,
+        len: usize,
+        expected: usize,
+    },
+    /// The threads to sort on couldn't be started.
+    ThreadPool(String),
+    /// The contrast map is combined with a mask image, but no image was given to [`PixelSorter::mask_image`].
+    MissingMask(String),
+    /// The mask image has no pixels.
+    EmptyMask(String),
+    /// The mask image isn't the same size as the image being sorted, and [`MaskResize::Error`] is set.
+    MaskSizeMismatch {
+        mask: (u32, u32),
+        image: (u32, u32),
+    },
+}
+
+impl fmt::Display for SortError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SortError::InvalidSettings(reason) => write!(f, "{}", reason),
+            SortError::MissingFlowMap(path) => write!(f, "The sort direction follows the flow map {}, but it wasn't loaded.", path),
+            SortError::EmptyFlowMap(path) => write!(f, "The flow map {} has no pixels.", path),
+            SortError::WrongLength { name, len, expected } => write!(f, "There are {} {}, but the image has {} pixels.", len, name, expected),
+            SortError::ThreadPool(reason) => write!(f, "The sorting threads couldn't be started. {}", reason),
+            SortError::MissingMask(path) => write!(f, "The contrast map is combined with the mask {}, but it wasn't loaded.", path),
+            SortError::EmptyMask(path) => write!(f, "The mask {} has no pixels.", path),
+            SortError::MaskSizeMismatch { mask, image } => write!(f, "The mask is {}x{}, but the image is {}x{}. Resize the mask or stretch it over the image.", mask.0, mask.1, image.0, image.1),
+        }
+    }
+}
+
+impl std::error::Error for SortError {}
+
+/// The result of a sort: the sorted image and the contrast map (row-major, `true` where pixels were eligible for sorting).
+#[derive(Debug, Clone)]
+pub struct SortOutput {
+    pub image: RgbaImage,
+    pub contrast_map: Vec<bool>,
+    /// The contrast value of every pixel (row-major), which the contrast map was built from. Render it with
+    /// [`contrast_values_image`].
+    pub contrast_values: Vec<f32>,
+    /// The (lower, upper) bounds the contrast map was built with, which [`AutoThreshold`] picks per image.
+    pub contrast_bounds: (f32, f32),
+}
+
+impl SortOutput {
+    /// Renders the contrast map with [`contrast_map_image`].
+    pub fn mask_image(&self) -> RgbaImage {
+        contrast_map_image(&self.contrast_map, self.image.width(), self.image.height())
+    }
+}
+
+/// Renders a row-major contrast map as a black and white image, white where pixels are eligible for sorting.
+pub fn contrast_map_image(contrast_map: &[bool], width: u32, height: u32) -> RgbaImage {
+    RgbaImage::from_fn(width, height, |x, y| {
+        if contrast_map[y as usize*width as usize + x as usize] {Rgba([255, 255, 255, 255])} else {Rgba([0, 0, 0, 255])}
+    })
+}
+
+/// Renders row-major contrast values as a greyscale image, black at 0.0 and white at 1.0 or above.
+pub fn contrast_values_image(contrast_values: &[f32], width: u32, height: u32) -> RgbaImage {
+    RgbaImage::from_fn(width, height, |x, y| {
+        let grey = (contrast_values[y as usize*width as usize + x as usize].clamp(0.0, 1.0)*255.0).round() as u8;
+        Rgba([grey, grey, grey, 255])
+    })
+}
@@ -1,936 +1,543 @@
-use std::{cmp::Reverse, fs};
-use std::fs::File;
-use std::io::Write;
-use std::path::{Path, MAIN_SEPARATOR_STR};
-use image::{Rgba, DynamicImage, GenericImageView, GenericImage};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use clap::{Args, Parser, Subcommand};
+use image::DynamicImage;
+use serde::de::{value, DeserializeOwned, IntoDeserializer};
+use serde::Deserialize;
+use sorting_pixels_experiment::{channel_stats, contrast_map_image, contrast_values_image, AutoThreshold, ConfigFormat, ContrastType, FlowSource, MaskCombine, MaskResize, MaskSource, PixelSorter, ProgramSettings, SortBy, SortDirection, SortOrder, SpanMode, SpanSplit, TieBreaker, DEFAULT_CONFIG};
+
+const CONFIG_PATH: &str = "settings.toml";
+const JSON_CONFIG_PATH: &str = "settings.json";
+const LEGACY_CONFIG_PATH: &str = "settings.txt";
+
+#[derive(Parser)]
+#[command(version, about = "Sorts spans of pixels in every image of a folder")]
+struct Cli {
+    /// The settings file to read (TOML, JSON or a 7-line settings.txt). Defaults to settings.toml, settings.json or settings.txt in the current directory
+    #[arg(short, long, global = true)]
+    config: Option<PathBuf>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Sort every image in the input path (the default when no command is given)
+    Sort {
+        #[command(flatten)]
+        overrides: SettingsOverrides,
+    },
+    /// Write only the contrast map of every image in the input path
+    Mask {
+        #[command(flatten)]
+        overrides: SettingsOverrides,
+    },
+    /// Print statistics of every contrast type for an image, on the same 0.0-1.0 scale as the contrast map bounds
+    Inspect {
+        image: PathBuf,
+    },
+    /// Write a commented default config (plain defaults when the path ends in .json). The path must end in .toml or .json
+    Init {
+        #[arg(default_value = CONFIG_PATH)]
+        path: PathBuf,
+        /// Overwrite the file if it already exists
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+/// Spelled out so clap takes the whole list as one value rather than one value per flag.
+type ContrastRanges = Vec<(f32, f32)>;
+
+/// Per-run overrides for every settings file key, named the same as the keys. Enum values take the same names as the settings file.
+#[derive(Args, Default)]
+struct SettingsOverrides {
+    /// Overrides input_path. Either a folder of images or a single image
+    #[arg(long)]
+    input_path: Option<String>,
+    /// Overrides sort_direction
+    #[arg(long, value_parser = parse_setting::<SortDirection>)]
+    sort_direction: Option<SortDirection>,
+    /// Overrides sort_order
+    #[arg(long, value_parser = parse_setting::<SortOrder>)]
+    sort_order: Option<SortOrder>,
+    /// Overrides sort_by
+    #[arg(long, value_parser = parse_setting::<SortBy>)]
+    sort_by: Option<SortBy>,
+    /// Overrides tie_breakers, once per tie breaker, e.g. --tie-breaker '{ key = "value", order = "descending" }'
+    #[arg(long = "tie-breaker", value_parser = parse_setting::<TieBreaker>)]
+    tie_breakers: Option<Vec<TieBreaker>>,
+    /// Overrides stable_sort
+    #[arg(long)]
+    stable_sort: Option<bool>,
+    /// Overrides hue_origin
+    #[arg(long, allow_negative_numbers = true)]
+    hue_origin: Option<f32>,
+    /// Overrides contrast_map_lower
+    #[arg(long, allow_negative_numbers = true)]
+    contrast_map_lower: Option<f32>,
+    /// Overrides contrast_map_upper
+    #[arg(long, allow_negative_numbers = true)]
+    contrast_map_upper: Option<f32>,
+    /// Overrides auto_threshold
+    #[arg(long, value_parser = parse_setting::<AutoThreshold>)]
+    auto_threshold: Option<AutoThreshold>,
+    /// Overrides contrast_map_ranges, e.g. '[[0.0, 0.1], [0.8, 0.9]]'
+    #[arg(long, value_parser = parse_setting::<ContrastRanges>)]
+    contrast_map_ranges: Option<ContrastRanges>,
+    /// Overrides invert_contrast_map
+    #[arg(long)]
+    invert_contrast_map: Option<bool>,
+    /// Overrides contrast_type
+    #[arg(long, value_parser = parse_setting::<ContrastType>)]
+    contrast_type: Option<ContrastType>,
+    /// Overrides mask, e.g. '{ image = "mask.png" }' or alpha
+    #[arg(long, value_parser = parse_setting::<MaskSource>)]
+    mask: Option<MaskSource>,
+    /// Overrides mask_threshold
+    #[arg(long, allow_negative_numbers = true)]
+    mask_threshold: Option<f32>,
+    /// Overrides mask_combine
+    #[arg(long, value_parser = parse_setting::<MaskCombine>)]
+    mask_combine: Option<MaskCombine>,
+    /// Overrides mask_resize
+    #[arg(long, value_parser = parse_setting::<MaskResize>)]
+    mask_resize: Option<MaskResize>,
+    /// Overrides span_mode, e.g. '{ hysteresis = { high = 0.8 } }' or '{ difference = { delta = 0.1 } }'
+    #[arg(long, value_parser = parse_setting::<SpanMode>)]
+    span_mode: Option<SpanMode>,
+    /// Overrides min_span_length
+    #[arg(long)]
+    min_span_length: Option<usize>,
+    /// Overrides max_span_length
+    #[arg(long)]
+    max_span_length: Option<usize>,
+    /// Overrides span_split, e.g. '{ exponential = { mean = 50.0 } }'
+    #[arg(long, value_parser = parse_setting::<SpanSplit>)]
+    span_split: Option<SpanSplit>,
+    /// Overrides should_debug
+    #[arg(long)]
+    should_debug: Option<bool>,
+    /// Overrides threads
+    #[arg(long)]
+    threads: Option<usize>,
+    /// Overrides seed
+    #[arg(long)]
+    seed: Option<u64>,
+}
+
+impl SettingsOverrides {
+    /// Replaces every setting given on the command line.
+    fn apply(self, program_settings: &mut ProgramSettings) {
+        if let Some(input_path) = self.input_path {
+            program_settings.input_path = input_path;
+        }
+        if let Some(sort_direction) = self.sort_direction {
+            program_settings.sort_direction = sort_direction;
+        }
+        if let Some(sort_order) = self.sort_order {
+            program_settings.sort_order = sort_order;
+        }
+        if let Some(sort_by) = self.sort_by {
+            program_settings.sort_by = sort_by;
+        }
+        if let Some(tie_breakers) = self.tie_breakers {
+            program_settings.tie_breakers = tie_breakers;
+        }
+        if let Some(stable_sort) = self.stable_sort {
+            program_settings.stable_sort = stable_sort;
+        }
+        if let Some(hue_origin) = self.hue_origin {
+            program_settings.hue_origin = hue_origin;
+        }
+        if let Some(contrast_map_lower) = self.contrast_map_lower {
+            program_settings.contrast_map_lower = contrast_map_lower;
+        }
+        if let Some(contrast_map_upper) = self.contrast_map_upper {
+            program_settings.contrast_map_upper = contrast_map_upper;
+        }
+        if let Some(auto_threshold) = self.auto_threshold {
+            program_settings.auto_threshold = auto_threshold;
+        }
+        if let Some(contrast_map_ranges) = self.contrast_map_ranges {
+            program_settings.contrast_map_ranges = contrast_map_ranges;
+        }
+        if let Some(invert_contrast_map) = self.invert_contrast_map {
+            program_settings.invert_contrast_map = invert_contrast_map;
+        }
+        if let Some(contrast_type) = self.contrast_type {
+            program_settings.contrast_type = contrast_type;
+        }
+        if let Some(mask) = self.mask {
+            program_settings.mask = mask;
+        }
+        if let Some(mask_threshold) = self.mask_threshold {
+            program_settings.mask_threshold = mask_threshold;
+        }
+        if let Some(mask_combine) = self.mask_combine {
+            program_settings.mask_combine = mask_combine;
+        }
+        if let Some(mask_resize) = self.mask_resize {
+            program_settings.mask_resize = mask_resize;
+        }
+        if let Some(span_mode) = self.span_mode {
+            program_settings.span_mode = span_mode;
+        }
+        if let Some(min_span_length) = self.min_span_length {
+            program_settings.min_span_length = min_span_length;
+        }
+        if let Some(max_span_length) = self.max_span_length {
+            program_settings.max_span_length = max_span_length;
+        }
+        if let Some(span_split) = self.span_split {
+            program_settings.span_split = span_split;
+        }
+        if let Some(should_debug) = self.should_debug {
+            program_settings.should_debug = should_debug;
+        }
+        if let Some(threads) = self.threads {
+            program_settings.threads = threads;
+        }
+        if let Some(seed) = self.seed {
+            program_settings.seed = seed;
+        }
+    }
+}
+
+/// Parses a flag the way the settings file would: a bare name such as "left", or any other inline TOML value.
+fn parse_setting<T: DeserializeOwned>(setting: &str) -> Result<T, String> {
+    #[derive(Deserialize)]
+    struct Inline<T> {
+        value: T,
+    }
+
+    if setting.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+        return T::deserialize(IntoDeserializer::<value::Error>::into_deserializer(setting)).map_err(|err| err.to_string());
+    }
+    toml::from_str::<Inline<T>>(&format!("value = {}", setting)).map(|inline| inline.value).map_err(|err| err.message().to_string())
+}
 
 fn main() {
-    // Check if the settings file exists
-    if File::open("settings.txt").is_err() {
-        // If it doesn't exist, create it with default settings
-        let mut file = File::create("settings.txt").unwrap();
-        let default_settings_string = "input //The path where film/image(s) are, as well as where the program will output the result\nleft //The sort direction (Possible values:left,right,down,up)\nred //What value to sort by (Possible values:red,green,blue,hue,saturation,value)\n0.5 //The lower bound of values (Range: 0.0-1.0) (Anything more than this will get sorted)\n1.0 //The upper bound of values (Range: 0.0-1.0) (Anything less than this will get sorted)\nred //What value should be used to create the contrast map (Possible values:red,green,blue,hue,saturation,value)\nfalse //Should the program print debug messages and create debug images? (Either true or false)";
-        file.write_all(default_settings_string.as_bytes()).unwrap();
+    let cli = Cli::parse();
+    match cli.command.unwrap_or(Command::Sort { overrides: SettingsOverrides::default() }) {
+        Command::Sort { overrides } => {
+            if let Some(program_settings) = load_settings(cli.config.as_deref(), overrides) {
+                manage_sort(&program_settings, false);
+            }
+        },
+        Command::Mask { overrides } => {
+            if let Some(program_settings) = load_settings(cli.config.as_deref(), overrides) {
+                manage_sort(&program_settings, true);
+            }
+        },
+        Command::Inspect { image } => inspect(&image),
+        Command::Init { path, force } => init(&path, force),
     }
+}
+
+/// Reads the settings file and applies the overrides, printing why if the result can't be used.
+fn load_settings(config_path: Option<&Path>, overrides: SettingsOverrides) -> Option<ProgramSettings> {
+    let config_path = match config_path {
+        Some(config_path) => {
+            if !config_path.is_file() {
+                println!("The settings file {} does not exist.", config_path.display());
+                return None;
+            }
+            config_path.to_path_buf()
+        },
+        // Find the settings file, preferring the keyed formats over the legacy settings.txt
+        None => if Path::new(CONFIG_PATH).exists() {
+            PathBuf::from(CONFIG_PATH)
+        } else if Path::new(JSON_CONFIG_PATH).exists() {
+            PathBuf::from(JSON_CONFIG_PATH)
+        } else if Path::new(LEGACY_CONFIG_PATH).exists() {
+            PathBuf::from(LEGACY_CONFIG_PATH)
+        } else {
+            // If it doesn't exist, create it with default settings
+            fs::write(CONFIG_PATH, DEFAULT_CONFIG).unwrap();
+            PathBuf::from(CONFIG_PATH)
+        },
+    };
 
     // Load the settings from the settings file
-    let lines = fs::read_to_string("settings.txt").unwrap().lines().map(|x| x.split("//").next().unwrap().trim().to_string().to_ascii_lowercase()).collect::<Vec<String>>();
+    let contents = fs::read_to_string(&config_path).unwrap();
+    let format = ConfigFormat::from_path(&config_path);
+    let mut program_settings = match ProgramSettings::parse(&contents, format) {
+        Ok(program_settings) => program_settings,
+        Err(err) => {
+            println!("{} Please fix {} or delete it and run the program again to create a new one.", err, config_path.display());
+            return None;
+        }
+    };
 
-    // Error check the settings file
-    if lines.len() != 7 {
-        println!("The settings file is not formatted correctly or has the wrong amount of lines. Please make sure the number of lines inside the file is equal to 7 (No empty lines at the end of the file). Please delete it and run the program again to create a new one.");
-        return;
-    } else if lines[6] == "true" {
-        println!("{:?}, {:?}", lines, !Path::new(&lines[0]).exists() || !Path::new(&lines[0]).is_dir());
-    } else if !Path::new(&lines[0]).exists() || !Path::new(&lines[0]).is_dir() {
-        println!("The input path is not a directory or does not exist. Please delete it and run the program again to create a new one.");
-        return;
-    } else if lines[1] != "left" && lines[1] != "right" && lines[1] != "down" && lines[1] != "up" {
-        println!("The sort direction is not valid. Please make sure the value is supported and spelt correctly. Please delete it and run the program again to create a new one.");
-        return;
-    } else if lines[2] != "red" && lines[2] != "green" && lines[2] != "blue" && lines[2] != "hue" && lines[2] != "saturation" && lines[2] != "value" {
-        println!("The sort by value is not valid. Please make sure the value is supported and spelt correctly. Please delete it and run the program again to create a new one.");
-        return;
-    } else if lines[3].parse::<f32>().is_err() || lines[3].parse::<f32>().unwrap() < 0.0 || lines[3].parse::<f32>().unwrap() > 1.0 {
-        println!("The contrast map lower bound is not valid. Please make sure the value is a number between 0.0 and 1.0. Please delete it and run the program again to create a new one.");
-        return;
-    } else if lines[4].parse::<f32>().is_err() || lines[4].parse::<f32>().unwrap() < 0.0 || lines[4].parse::<f32>().unwrap() > 1.0 {
-        println!("The contrast map upper bound is not valid. Please make sure the value is a number between 0.0 and 1.0. Please delete it and run the program again to create a new one.");
+    if format == ConfigFormat::Legacy {
+        offer_migration(&config_path, &program_settings);
+    }
+
+    overrides.apply(&mut program_settings);
+    if let Err(err) = program_settings.validate() {
+        println!("{}", err);
+        return None;
+    }
+    if program_settings.should_debug {
+        println!("{:?}", program_settings);
+    }
+
+    if !Path::new(&program_settings.input_path).exists() {
+        println!("The input path does not exist. Please change it in {} or pass --input-path.", config_path.display());
+        return None;
+    }
+    Some(program_settings)
+}
+
+/// Asks whether a legacy settings file should be rewritten as TOML next to it (settings.txt becomes settings.toml, which is read instead from then on).
+fn offer_migration(config_path: &Path, program_settings: &ProgramSettings) {
+    let migrated_path = config_path.with_extension("toml");
+    if migrated_path.exists() {
         return;
-    } else if lines[5] != "red" && lines[5] != "green" && lines[5] != "blue" && lines[5] != "hue" && lines[5] != "saturation" && lines[5] != "value" {
-        println!("The contrast type is not valid. Please make sure the value is supported and spelt correctly. Please delete it and run the program again to create a new one.");
+    }
+
+    print!("{} uses the old 7-line format. Migrate it to {}? (y/n) ", config_path.display(), migrated_path.display());
+    io::stdout().flush().unwrap();
+    let mut answer = String::new();
+    if io::stdin().read_line(&mut answer).is_err() || !answer.trim().eq_ignore_ascii_case("y") {
         return;
-    } else if lines[6] != "true" && lines[6] != "false" {
-        println!("The debug value is not valid. Please make sure the value is either true or false. Please delete it and run the program again to create a new one.");
+    }
+
+    match fs::write(&migrated_path, program_settings.to_toml()) {
+        Ok(()) => println!("Wrote {}. {} is no longer needed and can be deleted.", migrated_path.display(), config_path.display()),
+        Err(err) => println!("Unable to write {}. {:?}", migrated_path.display(), err),
+    }
+}
+
+fn init(path: &Path, force: bool) {
+    if path.exists() && !force {
+        println!("{} already exists. Pass --force to overwrite it.", path.display());
         return;
     }
 
-    // Initialise the program settings and start the program
-    let program_settings = ProgramSettings {
-        input_path: lines[0].clone(),
-        sort_direction: match lines[1].as_str() {
-            "left" => SortDirection::Left,
-            "right" => SortDirection::Right,
-            "down" => SortDirection::Down,
-            "up" => SortDirection::Up,
-            _ => SortDirection::Left, 
-        },
-        sort_by: match lines[2].as_str() {
-            "red" => SortBy::Red,
-            "green" => SortBy::Green,
-            "blue" => SortBy::Blue,
-            "hue" => SortBy::Hue,
-            "saturation" => SortBy::Saturation,
-            "value" => SortBy::Value,
-            _ => SortBy::Red,
-        },
-        contrast_map_lower: lines[3].parse::<f32>().unwrap(),
-        contrast_map_upper: lines[4].parse::<f32>().unwrap(),
-        contrast_type: match lines[5].as_str() {
-            "red" => ContrastType::Red,
-            "green" => ContrastType::Green,
-            "blue" => ContrastType::Blue,
-            "hue" => ContrastType::Hue,
-            "saturation" => ContrastType::Saturation,
-            "value" => ContrastType::Value,
-            _ => ContrastType::Red,
-        },
-        should_debug: lines[6].parse().unwrap(),
+    let contents = match ConfigFormat::from_path(path) {
+        ConfigFormat::Toml => DEFAULT_CONFIG.to_string(),
+        ConfigFormat::Json => ProgramSettings::default().to_json() + "\n",
+        // Anything else would be read back as an old settings.txt, which can't hold most of the settings
+        ConfigFormat::Legacy => {
+            println!("{} isn't a .toml or .json file. Please pick a path ending in one of those.", path.display());
+            return;
+        }
     };
-    manage_sort(&program_settings);
+    match fs::write(path, contents) {
+        Ok(()) => println!("Wrote {}", path.display()),
+        Err(err) => println!("Unable to write {}. {:?}", path.display(), err),
+    }
+}
+
+fn inspect(image_path: &Path) {
+    let img = match image::open(image_path) {
+        Ok(img) => img.to_rgba8(),
+        Err(err) => {
+            println!("Unable to open {}. {}", image_path.display(), err);
+            return;
+        }
+    };
+
+    println!("{} ({}x{})", image_path.display(), img.width(), img.height());
+    println!("{:<16}{:>8}{:>8}{:>8}{:>8}{:>8}", "channel", "min", "max", "mean", "std dev", "median");
+    for (contrast_type, stats) in channel_stats(&img) {
+        // Named the way the settings file names them
+        let name = serde_json::to_value(contrast_type).unwrap().as_str().unwrap_or_default().to_string();
+        println!("{:<16}{:>8.3}{:>8.3}{:>8.3}{:>8.3}{:>8.3}", name, stats.min, stats.max, stats.mean, stats.std_dev, stats.median);
+    }
 }
 
-fn manage_sort(program_settings: &ProgramSettings) {
+/// Sorts (or only masks) every image in the input path, saving the results in an /out folder next to them.
+fn manage_sort(program_settings: &ProgramSettings, mask_only: bool) {
     let input_path = Path::new(&program_settings.input_path).canonicalize().unwrap();
-    let binding = input_path.clone().to_str().unwrap().to_string() + MAIN_SEPARATOR_STR + "out";
-    let output_path = Path::new(&binding);
- 
+    let (input_dir, image_paths) = if input_path.is_dir() {
+        let image_paths = fs::read_dir(&input_path).unwrap().map(|path| path.unwrap().path()).filter(|path| !path.is_dir()).collect::<Vec<PathBuf>>();
+        (input_path, image_paths)
+    } else {
+        (input_path.parent().unwrap().to_path_buf(), vec![input_path])
+    };
+    let output_path = input_dir.join("out");
+
     if !output_path.exists() {
-        let result = fs::create_dir(output_path);
-        if result.is_err() {
-            println!("Unable to create the output directory {:?}. {:?}", output_path.canonicalize().unwrap_err().kind(), result.err().unwrap());
+        let result = fs::create_dir(&output_path);
+        if let Err(err) = result {
+            println!("Unable to create the output directory {}. {:?}", output_path.display(), err);
             return;
         }
     }
 
-    let paths = fs::read_dir(input_path).unwrap();
-    for path in paths {
-        let unwrap_path = path.unwrap();
-        if !unwrap_path.path().is_dir() {
-            let path_string = unwrap_path.path().to_str().unwrap().to_string();
-            let output_path_and_name = output_path.to_str().unwrap().to_string().clone() + MAIN_SEPARATOR_STR + unwrap_path.path().file_name().unwrap().to_str().unwrap();
-            start_sort(program_settings, &path_string, &output_path_and_name);
+    let Some(sorter) = create_sorter(program_settings) else {
+        return;
+    };
+    for image_path in image_paths {
+        let output_path_and_name = output_path.join(image_path.file_name().unwrap()).to_str().unwrap().to_string();
+        start_sort(program_settings, &sorter, image_path.to_str().unwrap(), &output_path_and_name, mask_only);
+    }
+}
+
+/// Builds the sorter for the settings, loading any images the settings point to.
+fn create_sorter(program_settings: &ProgramSettings) -> Option<PixelSorter> {
+    let mut sorter = PixelSorter::from(program_settings);
+    if let SortDirection::Flow(FlowSource::Map(flow_map_path)) = &program_settings.sort_direction {
+        match image::open(flow_map_path) {
+            Ok(flow_map) => sorter = sorter.flow_map(flow_map.to_rgba8()),
+            Err(err) => {
+                println!("Unable to open the flow map {}. {}", flow_map_path, err);
+                return None;
+            }
+        }
+    }
+    if let MaskSource::Image(mask_path) = &program_settings.mask {
+        match image::open(mask_path) {
+            Ok(mask_image) => sorter = sorter.mask_image(mask_image.to_rgba8()),
+            Err(err) => {
+                println!("Unable to open the mask {}. {}", mask_path, err);
+                return None;
+            }
         }
     }
+    Some(sorter)
 }
 
-fn start_sort(program_settings: &ProgramSettings, input_image_path: &String, output_path_and_name: &String) {
-    // Open the image and get the pixels
+fn start_sort(program_settings: &ProgramSettings, sorter: &PixelSorter, input_image_path: &str, output_path_and_name: &str, mask_only: bool) {
+    // Open the image
     println!("Opening image: {}", input_image_path);
     let img: DynamicImage = image::open(input_image_path).unwrap();
-    let mut pixels_vec: Vec<Rgba<u8>> = img.pixels().map(|pixel| pixel.2).collect();
 
-    // Create a contrast map from the pixels
-    println!("Creating contrast map");
-    let mut contrast_map: Vec<bool> = Vec::with_capacity(pixels_vec.len());
-    create_contrast_map(program_settings, &pixels_vec, &mut contrast_map);
-
-    //Save the contrast map for debugging
-    if program_settings.should_debug {
+    if mask_only {
         println!("Saving contrast map");
-        let mut contrast_map_img: DynamicImage = DynamicImage::new_rgba8(img.width(), img.height());
-        contrast_map.iter().enumerate().for_each(|(i, pixel)| contrast_map_img.put_pixel((i%img.width() as usize) as u32, (i/img.width() as usize) as u32, if *pixel {Rgba([255, 255, 255, 255])} else {Rgba([0, 0, 0, 255])}));
-        contrast_map_img.save(output_path_and_name.clone() + "mask.png").unwrap();
+        let img = img.to_rgba8();
+        let (contrast_map, contrast_bounds) = match sorter.contrast_map_with_bounds(&img) {
+            Ok(contrast_map_with_bounds) => contrast_map_with_bounds,
+            Err(err) => {
+                println!("Unable to mask {}. {}", input_image_path, err);
+                return;
+            }
+        };
+        record_bounds(program_settings, contrast_bounds, output_path_and_name);
+        contrast_map_image(&contrast_map, img.width(), img.height()).save(output_path_and_name.to_string() + "mask.png").unwrap();
+        return;
     }
 
-    // Sort the pixels
+    // Create the contrast map and sort the pixels
     println!("Sorting pixels");
-    sort_pixels(program_settings, &mut pixels_vec, &contrast_map, img.width() as usize, img.height() as usize);
-
-    // Create a new image from the pixels
-    println!("Creating new image");
-    let mut new_img: DynamicImage = DynamicImage::new_rgba8(img.width(), img.height());
-    pixels_vec.iter().enumerate().for_each(|(i, pixel)| new_img.put_pixel((i%img.width() as usize) as u32, (i/img.width() as usize) as u32, pixel.clone()));
-    new_img.save(output_path_and_name).unwrap();
-
-    fn create_contrast_map(program_settings: &ProgramSettings, pixels_vec: &Vec<Rgba<u8>>, contrast_map: &mut Vec<bool>) {
-        match program_settings.contrast_type {
-            ContrastType::Red => {
-                pixels_vec.iter().for_each(|pixel| contrast_map.push((pixel[0] as f32/255.0) >= program_settings.contrast_map_lower && (pixel[0] as f32/255.0) <= program_settings.contrast_map_upper));
-            },
-            ContrastType::Green => {
-                pixels_vec.iter().for_each(|pixel| contrast_map.push((pixel[1] as f32/255.0) >= program_settings.contrast_map_lower && (pixel[1] as f32/255.0) <= program_settings.contrast_map_upper));
-            },
-            ContrastType::Blue => {
-                pixels_vec.iter().for_each(|pixel| contrast_map.push((pixel[2] as f32/255.0) >= program_settings.contrast_map_lower && (pixel[2] as f32/255.0) <= program_settings.contrast_map_upper));
-            },
-            ContrastType::Hue => {
-                pixels_vec.iter().for_each(|pixel| contrast_map.push(rgb_to_hsv(&pixel).0/360.0 >= program_settings.contrast_map_lower && rgb_to_hsv(&pixel).0/360.0 <= program_settings.contrast_map_upper));
-            },
-            ContrastType::Saturation => {
-                pixels_vec.iter().for_each(|pixel| contrast_map.push(rgb_to_hsv(&pixel).1/100.0 >= program_settings.contrast_map_lower && rgb_to_hsv(&pixel).1/100.0 <= program_settings.contrast_map_upper));
-            },
-            ContrastType::Value => {
-                pixels_vec.iter().for_each(|pixel| contrast_map.push(rgb_to_hsv(&pixel).2/100.0 >= program_settings.contrast_map_lower && rgb_to_hsv(&pixel).2/100.0 <= program_settings.contrast_map_upper));
-            },
+    let sorted = match sorter.sort_image(&img) {
+        Ok(sorted) => sorted,
+        Err(err) => {
+            println!("Unable to sort {}. {}", input_image_path, err);
+            return;
         }
-    }
+    };
 
-    fn sort_pixels(program_settings: &ProgramSettings, pixels_vec: &mut Vec<Rgba<u8>>, contrast_map: &Vec<bool>, width: usize, height: usize) {
-        match program_settings.sort_by {
-            SortBy::Red => {
-                match program_settings.sort_direction {
-                    SortDirection::Left => {
-                        for y in 0..height {
-                            // Get the row of pixels
-                            let mut row = pixels_vec[y*width..(y+1)*width].to_vec();
-                            // Sort the row only where spans of the contrast map are true
-                            let mut i = 0;
-                            while i < row.len() {
-                                if contrast_map[y*width+i] {
-                                    let mut j = i+1;
-                                    while j < row.len() && contrast_map[y*width+j] {
-                                        j += 1;
-                                    }
-                                    row[i..j].sort_unstable_by_key(|pixel| Reverse(pixel[0]));
-                                    i = j;
-                                } else {
-                                    i += 1;
-                                }
-                            }
-                            // Put the row back into the pixels vector
-                            pixels_vec[y*width..(y+1)*width].copy_from_slice(&row); 
-
-                            if program_settings.should_debug {
-                                println!("{}%", (y as f32/height as f32)*100.0);
-                            }
-                        }
-                    },
-                    SortDirection::Right => {
-                        for y in 0..height {
-                            // Get the row of pixels
-                            let mut row = pixels_vec[y*width..(y+1)*width].to_vec();
-                            // Sort the row only where spans of the contrast map are true
-                            let mut i = 0;
-                            while i < row.len() {
-                                if contrast_map[y*width+i] {
-                                    let mut j = i+1;
-                                    while j < row.len() && contrast_map[y*width+j] {
-                                        j += 1;
-                                    }
-                                    row[i..j].sort_unstable_by_key(|pixel| pixel[0]);
-                                    i = j;
-                                } else {
-                                    i += 1;
-                                }
-                            }
-                            // Put the row back into the pixels vector
-                            pixels_vec[y*width..(y+1)*width].copy_from_slice(&row); 
-
-                            if program_settings.should_debug {
-                                println!("{}%", (y as f32/height as f32)*100.0);
-                            }
-                        }
-                    },
-                    SortDirection::Up => {
-                        for x in 0..width {
-                            // Get the column of pixels
-                            let mut column: Vec<Rgba<u8>> = Vec::with_capacity(height);
-                            for y in 0..height {
-                                column.push(pixels_vec[y*width+x]);
-                            }
-                            // Sort the column only where spans of the contrast map are true
-                            let mut i = 0;
-                            while i < column.len() {
-                                if contrast_map[i*width+x] {
-                                    let mut j = i+1;
-                                    while j < column.len() && contrast_map[j*width+x] {
-                                        j += 1;
-                                    }
-                                    column[i..j].sort_unstable_by_key(|pixel| Reverse(pixel[0]));
-                                    i = j;
-                                } else {
-                                    i += 1;
-                                }
-                            }
-                            // Put the column back into the pixels vector
-                            for y in 0..height {
-                                pixels_vec[y*width+x] = column[y];
-                            }
-
-                            if program_settings.should_debug {
-                                println!("{}%", (x as f32/width as f32)*100.0);
-                            }
-                        }
-                    },
-                    SortDirection::Down => {
-                        for x in 0..width {
-                            // Get the column of pixels
-                            let mut column: Vec<Rgba<u8>> = Vec::with_capacity(height);
-                            for y in 0..height {
-                                column.push(pixels_vec[y*width+x]);
-                            }
-                            // Sort the column only where spans of the contrast map are true
-                            let mut i = 0;
-                            while i < column.len() {
-                                if contrast_map[i*width+x] {
-                                    let mut j = i+1;
-                                    while j < column.len() && contrast_map[j*width+x] {
-                                        j += 1;
-                                    }
-                                    column[i..j].sort_unstable_by_key(|pixel| pixel[0]);
-                                    i = j;
-                                } else {
-                                    i += 1;
-                                }
-                            }
-                            // Put the column back into the pixels vector
-                            for y in 0..height {
-                                pixels_vec[y*width+x] = column[y];
-                            }
-
-                            if program_settings.should_debug {
-                                println!("{}%", (x as f32/width as f32)*100.0);
-                            }
-                        }
-                    },
-                }
-            },
-            SortBy::Green => {
-                match program_settings.sort_direction {
-                    SortDirection::Left => {
-                        for y in 0..height {
-                            // Get the row of pixels
-                            let mut row = pixels_vec[y*width..(y+1)*width].to_vec();
-                            // Sort the row only where spans of the contrast map are true
-                            let mut i = 0;
-                            while i < row.len() {
-                                if contrast_map[y*width+i] {
-                                    let mut j = i+1;
-                                    while j < row.len() && contrast_map[y*width+j] {
-                                        j += 1;
-                                    }
-                                    row[i..j].sort_unstable_by_key(|pixel| Reverse(pixel[1]));
-                                    i = j;
-                                } else {
-                                    i += 1;
-                                }
-                            }
-                            // Put the row back into the pixels vector
-                            pixels_vec[y*width..(y+1)*width].copy_from_slice(&row); 
-
-                            if program_settings.should_debug {
-                                println!("{}%", (y as f32/height as f32)*100.0);
-                            }
-                        }
-                    },
-                    SortDirection::Right => {
-                        for y in 0..height {
-                            // Get the row of pixels
-                            let mut row = pixels_vec[y*width..(y+1)*width].to_vec();
-                            // Sort the row only where spans of the contrast map are true
-                            let mut i = 0;
-                            while i < row.len() {
-                                if contrast_map[y*width+i] {
-                                    let mut j = i+1;
-                                    while j < row.len() && contrast_map[y*width+j] {
-                                        j += 1;
-                                    }
-                                    row[i..j].sort_unstable_by_key(|pixel| pixel[1]);
-                                    i = j;
-                                } else {
-                                    i += 1;
-                                }
-                            }
-                            // Put the row back into the pixels vector
-                            pixels_vec[y*width..(y+1)*width].copy_from_slice(&row); 
-
-                            if program_settings.should_debug {
-                                println!("{}%", (y as f32/height as f32)*100.0);
-                            }
-                        }
-                    },
-                    SortDirection::Up => {
-                        for x in 0..width {
-                            // Get the column of pixels
-                            let mut column: Vec<Rgba<u8>> = Vec::with_capacity(height);
-                            for y in 0..height {
-                                column.push(pixels_vec[y*width+x]);
-                            }
-                            // Sort the column only where spans of the contrast map are true
-                            let mut i = 0;
-                            while i < column.len() {
-                                if contrast_map[i*width+x] {
-                                    let mut j = i+1;
-                                    while j < column.len() && contrast_map[j*width+x] {
-                                        j += 1;
-                                    }
-                                    column[i..j].sort_unstable_by_key(|pixel| Reverse(pixel[1]));
-                                    i = j;
-                                } else {
-                                    i += 1;
-                                }
-                            }
-                            // Put the column back into the pixels vector
-                            for y in 0..height {
-                                pixels_vec[y*width+x] = column[y];
-                            }
-
-                            if program_settings.should_debug {
-                                println!("{}%", (x as f32/width as f32)*100.0);
-                            }
-                        }
-                    },
-                    SortDirection::Down => {
-                        for x in 0..width {
-                            // Get the column of pixels
-                            let mut column: Vec<Rgba<u8>> = Vec::with_capacity(height);
-                            for y in 0..height {
-                                column.push(pixels_vec[y*width+x]);
-                            }
-                            // Sort the column only where spans of the contrast map are true
-                            let mut i = 0;
-                            while i < column.len() {
-                                if contrast_map[i*width+x] {
-                                    let mut j = i+1;
-                                    while j < column.len() && contrast_map[j*width+x] {
-                                        j += 1;
-                                    }
-                                    column[i..j].sort_unstable_by_key(|pixel| pixel[1]);
-                                    i = j;
-                                } else {
-                                    i += 1;
-                                }
-                            }
-                            // Put the column back into the pixels vector
-                            for y in 0..height {
-                                pixels_vec[y*width+x] = column[y];
-                            }
-
-                            if program_settings.should_debug {
-                                println!("{}%", (x as f32/width as f32)*100.0);
-                            }
-                        }
-                    },
-                }
-            },
-            SortBy::Blue => {
-                match program_settings.sort_direction {
-                    SortDirection::Left => {
-                        for y in 0..height {
-                            // Get the row of pixels
-                            let mut row = pixels_vec[y*width..(y+1)*width].to_vec();
-                            // Sort the row only where spans of the contrast map are true
-                            let mut i = 0;
-                            while i < row.len() {
-                                if contrast_map[y*width+i] {
-                                    let mut j = i+1;
-                                    while j < row.len() && contrast_map[y*width+j] {
-                                        j += 1;
-                                    }
-                                    row[i..j].sort_unstable_by_key(|pixel| Reverse(pixel[2]));
-                                    i = j;
-                                } else {
-                                    i += 1;
-                                }
-                            }
-                            // Put the row back into the pixels vector
-                            pixels_vec[y*width..(y+1)*width].copy_from_slice(&row); 
-
-                            if program_settings.should_debug {
-                                println!("{}%", (y as f32/height as f32)*100.0);
-                            }
-                        }
-                    },
-                    SortDirection::Right => {
-                        for y in 0..height {
-                            // Get the row of pixels
-                            let mut row = pixels_vec[y*width..(y+1)*width].to_vec();
-                            // Sort the row only where spans of the contrast map are true
-                            let mut i = 0;
-                            while i < row.len() {
-                                if contrast_map[y*width+i] {
-                                    let mut j = i+1;
-                                    while j < row.len() && contrast_map[y*width+j] {
-                                        j += 1;
-                                    }
-                                    row[i..j].sort_unstable_by_key(|pixel| pixel[2]);
-                                    i = j;
-                                } else {
-                                    i += 1;
-                                }
-                            }
-                            // Put the row back into the pixels vector
-                            pixels_vec[y*width..(y+1)*width].copy_from_slice(&row); 
-
-                            if program_settings.should_debug {
-                                println!("{}%", (y as f32/height as f32)*100.0);
-                            }
-                        }
-                    },
-                    SortDirection::Up => {
-                        for x in 0..width {
-                            // Get the column of pixels
-                            let mut column: Vec<Rgba<u8>> = Vec::with_capacity(height);
-                            for y in 0..height {
-                                column.push(pixels_vec[y*width+x]);
-                            }
-                            // Sort the column only where spans of the contrast map are true
-                            let mut i = 0;
-                            while i < column.len() {
-                                if contrast_map[i*width+x] {
-                                    let mut j = i+1;
-                                    while j < column.len() && contrast_map[j*width+x] {
-                                        j += 1;
-                                    }
-                                    column[i..j].sort_unstable_by_key(|pixel| Reverse(pixel[2]));
-                                    i = j;
-                                } else {
-                                    i += 1;
-                                }
-                            }
-                            // Put the column back into the pixels vector
-                            for y in 0..height {
-                                pixels_vec[y*width+x] = column[y];
-                            }
-
-                            if program_settings.should_debug {
-                                println!("{}%", (x as f32/width as f32)*100.0);
-                            }
-                        }
-                    },
-                    SortDirection::Down => {
-                        for x in 0..width {
-                            // Get the column of pixels
-                            let mut column: Vec<Rgba<u8>> = Vec::with_capacity(height);
-                            for y in 0..height {
-                                column.push(pixels_vec[y*width+x]);
-                            }
-                            // Sort the column only where spans of the contrast map are true
-                            let mut i = 0;
-                            while i < column.len() {
-                                if contrast_map[i*width+x] {
-                                    let mut j = i+1;
-                                    while j < column.len() && contrast_map[j*width+x] {
-                                        j += 1;
-                                    }
-                                    column[i..j].sort_unstable_by_key(|pixel| pixel[2]);
-                                    i = j;
-                                } else {
-                                    i += 1;
-                                }
-                            }
-                            // Put the column back into the pixels vector
-                            for y in 0..height {
-                                pixels_vec[y*width+x] = column[y];
-                            }
-
-                            if program_settings.should_debug {
-                                println!("{}%", (x as f32/width as f32)*100.0);
-                            }
-                        }
-                    },
-                }
-            },
-            SortBy::Hue => {
-                match program_settings.sort_direction {
-                    SortDirection::Left => {
-                        for y in 0..height {
-                            // Get the row of pixels
-                            let mut row = pixels_vec[y*width..(y+1)*width].to_vec();
-                            // Sort the row only where spans of the contrast map are true
-                            let mut i = 0;
-                            while i < row.len() {
-                                if contrast_map[y*width+i] {
-                                    let mut j = i+1;
-                                    while j < row.len() && contrast_map[y*width+j] {
-                                        j += 1;
-                                    }
-                                    row[i..j].sort_unstable_by(|a, b| rgb_to_hsv(a).0.partial_cmp(&rgb_to_hsv(b).0).unwrap());
-                                    i = j;
-                                } else {
-                                    i += 1;
-                                }
-                            }
-                            // Put the row back into the pixels vector
-                            pixels_vec[y*width..(y+1)*width].copy_from_slice(&row); 
-
-                            if program_settings.should_debug {
-                                println!("{}%", (y as f32/height as f32)*100.0);
-                            }
-                        }
-                    },
-                    SortDirection::Right => {
-                        for y in 0..height {
-                            // Get the row of pixels
-                            let mut row = pixels_vec[y*width..(y+1)*width].to_vec();
-                            // Sort the row only where spans of the contrast map are true
-                            let mut i = 0;
-                            while i < row.len() {
-                                if contrast_map[y*width+i] {
-                                    let mut j = i+1;
-                                    while j < row.len() && contrast_map[y*width+j] {
-                                        j += 1;
-                                    }
-                                    row[i..j].sort_unstable_by(|a, b| rgb_to_hsv(a).0.partial_cmp(&rgb_to_hsv(b).0).unwrap());
-                                    i = j;
-                                } else {
-                                    i += 1;
-                                }
-                            }
-                            // Put the row back into the pixels vector
-                            pixels_vec[y*width..(y+1)*width].copy_from_slice(&row); 
-
-                            if program_settings.should_debug {
-                                println!("{}%", (y as f32/height as f32)*100.0);
-                            }
-                        }
-                    },
-                    SortDirection::Up => {
-                        for x in 0..width {
-                            // Get the column of pixels
-                            let mut column: Vec<Rgba<u8>> = Vec::with_capacity(height);
-                            for y in 0..height {
-                                column.push(pixels_vec[y*width+x]);
-                            }
-                            // Sort the column only where spans of the contrast map are true
-                            let mut i = 0;
-                            while i < column.len() {
-                                if contrast_map[i*width+x] {
-                                    let mut j = i+1;
-                                    while j < column.len() && contrast_map[j*width+x] {
-                                        j += 1;
-                                    }
-                                    column[i..j].sort_unstable_by(|a, b| rgb_to_hsv(a).0.partial_cmp(&rgb_to_hsv(b).0).unwrap());
-                                    i = j;
-                                } else {
-                                    i += 1;
-                                }
-                            }
-                            // Put the column back into the pixels vector
-                            for y in 0..height {
-                                pixels_vec[y*width+x] = column[y];
-                            }
-
-                            if program_settings.should_debug {
-                                println!("{}%", (x as f32/width as f32)*100.0);
-                            }
-                        }
-                    },
-                    SortDirection::Down => {
-                        for x in 0..width {
-                            // Get the column of pixels
-                            let mut column: Vec<Rgba<u8>> = Vec::with_capacity(height);
-                            for y in 0..height {
-                                column.push(pixels_vec[y*width+x]);
-                            }
-                            // Sort the column only where spans of the contrast map are true
-                            let mut i = 0;
-                            while i < column.len() {
-                                if contrast_map[i*width+x] {
-                                    let mut j = i+1;
-                                    while j < column.len() && contrast_map[j*width+x] {
-                                        j += 1;
-                                    }
-                                    column[i..j].sort_unstable_by(|a, b| rgb_to_hsv(a).0.partial_cmp(&rgb_to_hsv(b).0).unwrap());
-                                    i = j;
-                                } else {
-                                    i += 1;
-                                }
-                            }
-                            // Put the column back into the pixels vector
-                            for y in 0..height {
-                                pixels_vec[y*width+x] = column[y];
-                            }
-
-                            if program_settings.should_debug {
-                                println!("{}%", (x as f32/width as f32)*100.0);
-                            }
-                        }
-                    },
-                }
-            },
-            SortBy::Saturation => {
-                match program_settings.sort_direction {
-                    SortDirection::Left => {
-                        for y in 0..height {
-                            // Get the row of pixels
-                            let mut row = pixels_vec[y*width..(y+1)*width].to_vec();
-                            // Sort the row only where spans of the contrast map are true
-                            let mut i = 0;
-                            while i < row.len() {
-                                if contrast_map[y*width+i] {
-                                    let mut j = i+1;
-                                    while j < row.len() && contrast_map[y*width+j] {
-                                        j += 1;
-                                    }
-                                    row[i..j].sort_unstable_by(|a, b| rgb_to_hsv(a).1.partial_cmp(&rgb_to_hsv(b).1).unwrap());
-                                    i = j;
-                                } else {
-                                    i += 1;
-                                }
-                            }
-                            // Put the row back into the pixels vector
-                            pixels_vec[y*width..(y+1)*width].copy_from_slice(&row); 
-
-                            if program_settings.should_debug {
-                                println!("{}%", (y as f32/height as f32)*100.0);
-                            }
-                        }
-                    },
-                    SortDirection::Right => {
-                        for y in 0..height {
-                            // Get the row of pixels
-                            let mut row = pixels_vec[y*width..(y+1)*width].to_vec();
-                            // Sort the row only where spans of the contrast map are true
-                            let mut i = 0;
-                            while i < row.len() {
-                                if contrast_map[y*width+i] {
-                                    let mut j = i+1;
-                                    while j < row.len() && contrast_map[y*width+j] {
-                                        j += 1;
-                                    }
-                                    row[i..j].sort_unstable_by(|a, b| rgb_to_hsv(a).1.partial_cmp(&rgb_to_hsv(b).1).unwrap());
-                                    i = j;
-                                } else {
-                                    i += 1;
-                                }
-                            }
-                            // Put the row back into the pixels vector
-                            pixels_vec[y*width..(y+1)*width].copy_from_slice(&row); 
-
-                            if program_settings.should_debug {
-                                println!("{}%", (y as f32/height as f32)*100.0);
-                            }
-                        }
-                    },
-                    SortDirection::Up => {
-                        for x in 0..width {
-                            // Get the column of pixels
-                            let mut column: Vec<Rgba<u8>> = Vec::with_capacity(height);
-                            for y in 0..height {
-                                column.push(pixels_vec[y*width+x]);
-                            }
-                            // Sort the column only where spans of the contrast map are true
-                            let mut i = 0;
-                            while i < column.len() {
-                                if contrast_map[i*width+x] {
-                                    let mut j = i+1;
-                                    while j < column.len() && contrast_map[j*width+x] {
-                                        j += 1;
-                                    }
-                                    column[i..j].sort_unstable_by(|a, b| rgb_to_hsv(a).1.partial_cmp(&rgb_to_hsv(b).1).unwrap());
-                                    i = j;
-                                } else {
-                                    i += 1;
-                                }
-                            }
-                            // Put the column back into the pixels vector
-                            for y in 0..height {
-                                pixels_vec[y*width+x] = column[y];
-                            }
-
-                            if program_settings.should_debug {
-                                println!("{}%", (x as f32/width as f32)*100.0);
-                            }
-                        }
-                    },
-                    SortDirection::Down => {
-                        for x in 0..width {
-                            // Get the column of pixels
-                            let mut column: Vec<Rgba<u8>> = Vec::with_capacity(height);
-                            for y in 0..height {
-                                column.push(pixels_vec[y*width+x]);
-                            }
-                            // Sort the column only where spans of the contrast map are true
-                            let mut i = 0;
-                            while i < column.len() {
-                                if contrast_map[i*width+x] {
-                                    let mut j = i+1;
-                                    while j < column.len() && contrast_map[j*width+x] {
-                                        j += 1;
-                                    }
-                                    column[i..j].sort_unstable_by(|a, b| rgb_to_hsv(a).1.partial_cmp(&rgb_to_hsv(b).1).unwrap());
-                                    i = j;
-                                } else {
-                                    i += 1;
-                                }
-                            }
-                            // Put the column back into the pixels vector
-                            for y in 0..height {
-                                pixels_vec[y*width+x] = column[y];
-                            }
-
-                            if program_settings.should_debug {
-                                println!("{}%", (x as f32/width as f32)*100.0);
-                            }
-                        }
-                    },
-                }
-            },
-            SortBy::Value => {
-                match program_settings.sort_direction {
-                    SortDirection::Left => {
-                        for y in 0..height {
-                            // Get the row of pixels
-                            let mut row = pixels_vec[y*width..(y+1)*width].to_vec();
-                            // Sort the row only where spans of the contrast map are true
-                            let mut i = 0;
-                            while i < row.len() {
-                                if contrast_map[y*width+i] {
-                                    let mut j = i+1;
-                                    while j < row.len() && contrast_map[y*width+j] {
-                                        j += 1;
-                                    }
-                                    row[i..j].sort_unstable_by(|a, b| rgb_to_hsv(a).2.partial_cmp(&rgb_to_hsv(b).2).unwrap());
-                                    i = j;
-                                } else {
-                                    i += 1;
-                                }
-                            }
-                            // Put the row back into the pixels vector
-                            pixels_vec[y*width..(y+1)*width].copy_from_slice(&row); 
-
-                            if program_settings.should_debug {
-                                println!("{}%", (y as f32/height as f32)*100.0);
-                            }
-                        }
-                    },
-                    SortDirection::Right => {
-                        for y in 0..height {
-                            // Get the row of pixels
-                            let mut row = pixels_vec[y*width..(y+1)*width].to_vec();
-                            // Sort the row only where spans of the contrast map are true
-                            let mut i = 0;
-                            while i < row.len() {
-                                if contrast_map[y*width+i] {
-                                    let mut j = i+1;
-                                    while j < row.len() && contrast_map[y*width+j] {
-                                        j += 1;
-                                    }
-                                    row[i..j].sort_unstable_by(|a, b| rgb_to_hsv(a).2.partial_cmp(&rgb_to_hsv(b).2).unwrap());
-                                    i = j;
-                                } else {
-                                    i += 1;
-                                }
-                            }
-                            // Put the row back into the pixels vector
-                            pixels_vec[y*width..(y+1)*width].copy_from_slice(&row); 
-
-                            if program_settings.should_debug {
-                                println!("{}%", (y as f32/height as f32)*100.0);
-                            }
-                        }
-                    },
-                    SortDirection::Up => {
-                        for x in 0..width {
-                            // Get the column of pixels
-                            let mut column: Vec<Rgba<u8>> = Vec::with_capacity(height);
-                            for y in 0..height {
-                                column.push(pixels_vec[y*width+x]);
-                            }
-                            // Sort the column only where spans of the contrast map are true
-                            let mut i = 0;
-                            while i < column.len() {
-                                if contrast_map[i*width+x] {
-                                    let mut j = i+1;
-                                    while j < column.len() && contrast_map[j*width+x] {
-                                        j += 1;
-                                    }
-                                    column[i..j].sort_unstable_by(|a, b| rgb_to_hsv(a).2.partial_cmp(&rgb_to_hsv(b).2).unwrap());
-                                    i = j;
-                                } else {
-                                    i += 1;
-                                }
-                            }
-                            // Put the column back into the pixels vector
-                            for y in 0..height {
-                                pixels_vec[y*width+x] = column[y];
-                            }
-
-                            if program_settings.should_debug {
-                                println!("{}%", (x as f32/width as f32)*100.0);
-                            }
-                        }
-                    },
-                    SortDirection::Down => {
-                        for x in 0..width {
-                            // Get the column of pixels
-                            let mut column: Vec<Rgba<u8>> = Vec::with_capacity(height);
-                            for y in 0..height {
-                                column.push(pixels_vec[y*width+x]);
-                            }
-                            // Sort the column only where spans of the contrast map are true
-                            let mut i = 0;
-                            while i < column.len() {
-                                if contrast_map[i*width+x] {
-                                    let mut j = i+1;
-                                    while j < column.len() && contrast_map[j*width+x] {
-                                        j += 1;
-                                    }
-                                    column[i..j].sort_unstable_by(|a, b| rgb_to_hsv(a).2.partial_cmp(&rgb_to_hsv(b).2).unwrap());
-                                    i = j;
-                                } else {
-                                    i += 1;
-                                }
-                            }
-                            // Put the column back into the pixels vector
-                            for y in 0..height {
-                                pixels_vec[y*width+x] = column[y];
-                            }
-
-                            if program_settings.should_debug {
-                                println!("{}%", (x as f32/width as f32)*100.0);
-                            }
-                        }
-                    },
-                }
-            },
+    record_bounds(program_settings, sorted.contrast_bounds, output_path_and_name);
+
+    //Save the contrast map for debugging
+    if program_settings.should_debug {
+        println!("Saving contrast map");
+        sorted.mask_image().save(output_path_and_name.to_string() + "mask.png").unwrap();
+        if let ContrastType::Edges { .. } = program_settings.contrast_type {
+            println!("Saving edges");
+            contrast_values_image(&sorted.contrast_values, sorted.image.width(), sorted.image.height()).save(output_path_and_name.to_string() + "edges.png").unwrap();
         }
     }
+
+    // Save the new image
+    println!("Saving new image");
+    sorted.image.save(output_path_and_name).unwrap();
 }
 
-fn rgb_to_hsv(colour: &Rgba<u8>) -> (f32, f32, f32) {
-    let r: f32 = colour[0] as f32/255.0;
-    let g: f32 = colour[1] as f32/255.0;
-    let b: f32 = colour[2] as f32/255.0;
-
-    let max: f32 = r.max(g).max(b);
-    let min: f32 = r.min(g).min(b);
-    let delta: f32 = max - min;
-
-    let mut h: f32 = 0.0;
-    if max == min {
-        h = 0.0;
-    } else if max == r {
-        h = (60.0 * ((g - b) / delta) + 360.0) % 360.0;
-    } else if max == g {
-        h = (60.0 * ((b - r) / delta) + 120.0) % 360.0;
-    } else if max == b {
-        h = (60.0 * ((r - g) / delta) + 240.0) % 360.0;
+/// Prints the bounds an auto threshold picked and saves the settings with them filled in next to the output, so the run
+/// can be repeated without the auto threshold.
+fn record_bounds(program_settings: &ProgramSettings, (lower, upper): (f32, f32), output_path_and_name: &str) {
+    if program_settings.auto_threshold == AutoThreshold::Off {
+        return;
     }
+    println!("Picked contrast map bounds of {} to {}", lower, upper);
 
-    let s: f32;
-    if max == 0.0 {
-        s = 0.0;
-    } else {
-        s = (delta / max) * 100.0;
+    let settings_path = output_path_and_name.to_string() + "settings.toml";
+    let picked_settings = ProgramSettings { contrast_map_lower: lower, contrast_map_upper: upper, auto_threshold: AutoThreshold::Off, ..program_settings.clone() };
+    // Only save settings that can be read back in
+    if let Err(err) = picked_settings.validate() {
+        println!("Unable to write {}. {}", settings_path, err);
+        return;
+    }
+    if let Err(err) = fs::write(&settings_path, picked_settings.to_toml()) {
+        println!("Unable to write {}. {:?}", settings_path, err);
     }
-
-    return (h, s, max * 100.0);
-}
-
-struct ProgramSettings {
-    input_path: String,
-    sort_direction: SortDirection,
-    sort_by: SortBy,
-    contrast_map_lower: f32,
-    contrast_map_upper: f32,
-    contrast_type: ContrastType,
-    should_debug: bool,
 }
 
-enum SortDirection {
-    Left,
-    Right,
-    Up,
-    Down
-}
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+    use sorting_pixels_experiment::{AutoThreshold, KeyOrder, MaskSource, ProgramSettings, SortBy, SortDirection, SortOrder, SpanMode, TieBreaker};
+    use super::{Cli, Command};
+
+    fn overridden(args: &[&str]) -> ProgramSettings {
+        let cli = Cli::try_parse_from(["sorting_pixels_experiment", "sort"].iter().chain(args)).unwrap();
+        let Some(Command::Sort { overrides }) = cli.command else {
+            unreachable!("the sort command was given");
+        };
+        let mut program_settings = ProgramSettings::default();
+        overrides.apply(&mut program_settings);
+        program_settings
+    }
 
-enum SortBy {
-    Red,
-    Green,
-    Blue,
-    Hue,
-    Saturation,
-    Value,
-}
+    #[test]
+    fn every_kind_of_override_reaches_the_settings() {
+        let program_settings = overridden(&[
+            "--input-path", "Photos/In",
+            "--sort-direction", "down",
+            "--sort-order", "random",
+            "--sort-by", "{ expression = \"r - b\" }",
+            "--tie-breaker", "{ key = \"value\" }",
+            "--tie-breaker", "{ key = \"hue\", order = \"descending\" }",
+            "--stable-sort", "true",
+            "--hue-origin", "-30",
+            "--contrast-map-upper", "0.75",
+            "--auto-threshold", "otsu",
+            "--contrast-map-ranges", "[[0.0, 0.1], [0.8, 0.9]]",
+            "--mask", "alpha",
+            "--span-mode", "{ hysteresis = { high = 0.8 } }",
+            "--max-span-length", "18446744073709551615",
+            "--seed", "18446744073709551615",
+        ]);
+        let expected = ProgramSettings {
+            input_path: "Photos/In".to_string(),
+            sort_direction: SortDirection::Down,
+            sort_order: SortOrder::Random,
+            sort_by: program_settings.sort_by.clone(),
+            tie_breakers: vec![
+                TieBreaker { key: SortBy::Value, order: KeyOrder::Ascending },
+                TieBreaker { key: SortBy::Hue, order: KeyOrder::Descending },
+            ],
+            stable_sort: true,
+            hue_origin: -30.0,
+            contrast_map_upper: 0.75,
+            auto_threshold: AutoThreshold::Otsu,
+            contrast_map_ranges: vec![(0.0, 0.1), (0.8, 0.9)],
+            mask: MaskSource::Alpha,
+            span_mode: SpanMode::Hysteresis { high: 0.8 },
+            max_span_length: usize::MAX,
+            seed: u64::MAX,
+            ..ProgramSettings::default()
+        };
+        assert!(matches!(&program_settings.sort_by, SortBy::Expression(expression) if expression.source() == "r - b"));
+        assert_eq!(program_settings, expected);
+    }
 
-enum ContrastType {
-    Red,
-    Green,
-    Blue,
-    Hue,
-    Saturation,
-    Value,
+    #[test]
+    fn no_overrides_leave_the_settings_alone() {
+        // Values TOML can't hold, such as the largest seed, pass through untouched
+        let mut program_settings = ProgramSettings { seed: u64::MAX, threads: usize::MAX, ..ProgramSettings::default() };
+        let cli = Cli::try_parse_from(["sorting_pixels_experiment", "sort"]).unwrap();
+        let Some(Command::Sort { overrides }) = cli.command else {
+            unreachable!("the sort command was given");
+        };
+        overrides.apply(&mut program_settings);
+        assert_eq!(program_settings, ProgramSettings { seed: u64::MAX, threads: usize::MAX, ..ProgramSettings::default() });
+    }
 }
@@ -0,0 +1,97 @@
+use image::RgbaImage;
+use crate::colour::luma;
+use crate::{MaskCombine, MaskResize, MaskSource, PixelSorter, SortError};
+
+/// Combines the contrast map with the mask, if there is one. A pixel is in the mask when the mask image's grey level
+/// (or the alpha of the image being sorted) is at least the threshold.
+pub(crate) fn apply_mask(sorter: &PixelSorter, img: &RgbaImage, contrast_map: &mut [bool]) -> Result<(), SortError> {
+    let (width, height) = img.dimensions();
+    let mask: Vec<bool> = match &sorter.settings.mask {
+        MaskSource::Off => return Ok(()),
+        MaskSource::Alpha => img.pixels().map(|pixel| pixel[3] as f32/255.0 >= sorter.settings.mask_threshold).collect(),
+        MaskSource::Image(path) => {
+            let mask_image = sorter.mask_image.as_ref().ok_or_else(|| SortError::MissingMask(path.clone()))?;
+            if mask_image.width() == 0 || mask_image.height() == 0 {
+                return Err(SortError::EmptyMask(path.clone()));
+            }
+            if mask_image.dimensions() != img.dimensions() && sorter.settings.mask_resize == MaskResize::Error {
+                return Err(SortError::MaskSizeMismatch { mask: mask_image.dimensions(), image: img.dimensions() });
+            }
+            // Stretched over the image the same way as a flow map, taking the nearest mask pixel
+            (0..height).flat_map(|y| (0..width).map(move |x| (x, y))).map(|(x, y)| {
+                let mask_x = (x as u64*mask_image.width() as u64/width as u64) as u32;
+                let mask_y = (y as u64*mask_image.height() as u64/height as u64) as u32;
+                luma(mask_image.get_pixel(mask_x, mask_y)) >= sorter.settings.mask_threshold
+            }).collect()
+        }
+    };
+
+    for (in_map, in_mask) in contrast_map.iter_mut().zip(mask) {
+        *in_map = match sorter.settings.mask_combine {
+            MaskCombine::Replace => in_mask,
+            MaskCombine::And => *in_map && in_mask,
+            MaskCombine::Or => *in_map || in_mask,
+        };
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgba, RgbaImage};
+    use crate::{ContrastType, MaskCombine, MaskResize, MaskSource, PixelSorter, SortError};
+
+    /// A row of pixels with the given grey levels.
+    fn greys(levels: &[u8]) -> RgbaImage {
+        RgbaImage::from_fn(levels.len() as u32, 1, |x, _| {
+            let level = levels[x as usize];
+            Rgba([level, level, level, 255])
+        })
+    }
+
+    /// A sorter whose contrast map is the pixels at least half way to white, masked by the image.
+    fn masked(combine: MaskCombine, mask_image: RgbaImage) -> PixelSorter {
+        PixelSorter::new()
+            .contrast_type(ContrastType::Red)
+            .contrast_bounds(0.5, 1.0)
+            .mask(MaskSource::Image("mask.png".to_string()), combine)
+            .mask_image(mask_image)
+    }
+
+    #[test]
+    fn masks_replace_or_combine_with_the_contrast_map() {
+        let img = greys(&[0, 255, 0, 255]);
+        let mask_image = greys(&[0, 0, 255, 255]);
+        assert_eq!(masked(MaskCombine::Replace, mask_image.clone()).contrast_map(&img).unwrap(), [false, false, true, true]);
+        assert_eq!(masked(MaskCombine::And, mask_image.clone()).contrast_map(&img).unwrap(), [false, false, false, true]);
+        assert_eq!(masked(MaskCombine::Or, mask_image).contrast_map(&img).unwrap(), [false, true, true, true]);
+    }
+
+    #[test]
+    fn a_mask_of_the_wrong_size_is_an_error_unless_stretched() {
+        let sorter = masked(MaskCombine::Replace, greys(&[0, 255]));
+        assert_eq!(sorter.contrast_map(&greys(&[0; 4])), Err(SortError::MaskSizeMismatch { mask: (2, 1), image: (4, 1) }));
+        // Each pixel takes the nearest mask pixel, whether the mask is stretched or shrunk
+        let sorter = sorter.mask_resize(MaskResize::Stretch);
+        assert_eq!(sorter.contrast_map(&greys(&[0; 4])).unwrap(), [false, false, true, true]);
+        let sorter = masked(MaskCombine::Replace, greys(&[255, 0, 0, 255])).mask_resize(MaskResize::Stretch);
+        assert_eq!(sorter.contrast_map(&greys(&[0; 2])).unwrap(), [true, false]);
+    }
+
+    #[test]
+    fn alpha_masks_take_the_pixels_at_least_as_opaque_as_the_threshold() {
+        let img = RgbaImage::from_fn(4, 1, |x, _| Rgba([0, 0, 0, [0, 100, 128, 255][x as usize]]));
+        let sorter = PixelSorter::new().mask(MaskSource::Alpha, MaskCombine::Replace);
+        assert_eq!(sorter.clone().contrast_map(&img).unwrap(), [false, false, true, true]);
+        assert_eq!(sorter.mask_threshold(0.3).contrast_map(&img).unwrap(), [false, true, true, true]);
+    }
+
+    #[test]
+    fn empty_mask_is_an_error() {
+        let sorter = PixelSorter::new()
+            .mask(MaskSource::Image("mask.png".to_string()), MaskCombine::Replace)
+            .mask_resize(MaskResize::Stretch)
+            .mask_image(RgbaImage::new(0, 0));
+        assert!(matches!(sorter.sort_rgba(&RgbaImage::new(8, 8)), Err(SortError::EmptyMask(_))));
+    }
+}
@@ -0,0 +1,293 @@
+use std::fmt;
+use image::Rgba;
+use serde::de::{value, IntoDeserializer};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use crate::expression::Scanner;
+use crate::{contrast_value, ContrastType, ExpressionError};
+
+/// A test combining several contrast values, such as `value > 0.4 and saturation < 0.2` or
+/// `hue in 0.5..0.72 or red > 0.9`. It is parsed once and then checked for every pixel.
+///
+/// Each comparison names a contrast type and compares its value, on the same 0.0-1.0 scale as the contrast map bounds,
+/// with `<`, `<=`, `>`, `>=`, `=` or `!=`. `name in lower..upper` checks a value lies between two bounds (or outside
+/// them when the lower bound is above the upper one, like the contrast map bounds). Comparisons can be combined with
+/// `and`, `or`, `not` and brackets.
+#[derive(Clone)]
+pub struct Predicate {
+    source: String,
+    root: Box<Condition>,
+}
+
+#[derive(Debug, Clone)]
+enum Condition {
+    Compare(ContrastType, Comparison, f32),
+    Within(ContrastType, f32, f32),
+    Not(Box<Condition>),
+    And(Box<Condition>, Box<Condition>),
+    Or(Box<Condition>, Box<Condition>),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Comparison {
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+    Equal,
+    NotEqual,
+}
+
+impl Predicate {
+    pub fn parse(source: &str) -> Result<Self, ExpressionError> {
+        let mut parser = Parser { scanner: Scanner::new(source) };
+        let root = parser.or()?;
+        parser.scanner.finish()?;
+        Ok(Predicate { source: source.to_string(), root: Box::new(root) })
+    }
+
+    /// The predicate as it was written.
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    pub fn matches(&self, pixel: &Rgba<u8>) -> bool {
+        self.root.matches(pixel)
+    }
+}
+
+impl Condition {
+    fn matches(&self, pixel: &Rgba<u8>) -> bool {
+        match self {
+            Condition::Compare(contrast_type, comparison, bound) => {
+                let value = contrast_value(contrast_type, pixel);
+                match comparison {
+                    Comparison::Less => value < *bound,
+                    Comparison::LessOrEqual => value <= *bound,
+                    Comparison::Greater => value > *bound,
+                    Comparison::GreaterOrEqual => value >= *bound,
+                    Comparison::Equal => value == *bound,
+                    Comparison::NotEqual => value != *bound,
+                }
+            },
+            Condition::Within(contrast_type, lower, upper) => {
+                let value = contrast_value(contrast_type, pixel);
+                if lower > upper {
+                    value >= *lower || value <= *upper
+                } else {
+                    value >= *lower && value <= *upper
+                }
+            },
+            Condition::Not(condition) => !condition.matches(pixel),
+            Condition::And(left, right) => left.matches(pixel) && right.matches(pixel),
+            Condition::Or(left, right) => left.matches(pixel) || right.matches(pixel),
+        }
+    }
+}
+
+/// Recursive descent parser, lowest precedence first: `or`, then `and`, then `not`. The keywords can be written in
+/// any case. Every keyword and bracket nests a level deeper, the same as in an expression.
+struct Parser {
+    scanner: Scanner,
+}
+
+impl Parser {
+    /// Reads the keyword if it is next, without mistaking the start of a longer name for it.
+    fn keyword(&mut self, keyword: &str) -> bool {
+        let start = self.scanner.position();
+        match self.scanner.word() {
+            Some((_, word)) if word.eq_ignore_ascii_case(keyword) => true,
+            _ => {
+                self.scanner.rewind(start);
+                false
+            },
+        }
+    }
+
+    fn or(&mut self) -> Result<Condition, ExpressionError> {
+        let depth = self.scanner.depth();
+        let mut condition = self.and()?;
+        while self.keyword("or") {
+            self.scanner.nest()?;
+            condition = Condition::Or(Box::new(condition), Box::new(self.and()?));
+        }
+        self.scanner.unnest(depth);
+        Ok(condition)
+    }
+
+    fn and(&mut self) -> Result<Condition, ExpressionError> {
+        let depth = self.scanner.depth();
+        let mut condition = self.not()?;
+        while self.keyword("and") {
+            self.scanner.nest()?;
+            condition = Condition::And(Box::new(condition), Box::new(self.not()?));
+        }
+        self.scanner.unnest(depth);
+        Ok(condition)
+    }
+
+    fn not(&mut self) -> Result<Condition, ExpressionError> {
+        if self.keyword("not") {
+            let depth = self.scanner.depth();
+            self.scanner.nest()?;
+            let condition = self.not()?;
+            self.scanner.unnest(depth);
+            Ok(Condition::Not(Box::new(condition)))
+        } else {
+            self.comparison()
+        }
+    }
+
+    fn comparison(&mut self) -> Result<Condition, ExpressionError> {
+        if self.scanner.eat("(") {
+            let depth = self.scanner.depth();
+            self.scanner.nest()?;
+            let condition = self.or()?;
+            if !self.scanner.eat(")") {
+                return Err(self.scanner.error("Expected ')'".to_string()));
+            }
+            self.scanner.unnest(depth);
+            return Ok(condition);
+        }
+
+        let Some((start, name)) = self.scanner.word() else {
+            return Err(match self.scanner.peek() {
+                Some(c) => self.scanner.error(format!("Expected a contrast type or '(' but found '{}'", c)),
+                None => self.scanner.error("Expected a contrast type or '(' but the predicate ended".to_string()),
+            });
+        };
+        let contrast_type = ContrastType::deserialize(IntoDeserializer::<value::Error>::into_deserializer(name.as_str())).map_err(|_| {
+            // These need settings or the whole image, so they can't be named on their own
+            if ["edges", "expression", "predicate", "distance_to"].contains(&name.as_str()) {
+                self.scanner.error_at(start, format!("'{}' can't be used in a predicate, only contrast types of a single pixel can", name))
+            } else {
+                self.scanner.error_at(start, format!("Unknown contrast type '{}' (any contrast type name such as value or hue)", name))
+            }
+        })?;
+
+        if self.keyword("in") {
+            let lower = self.bound()?;
+            if !self.scanner.eat("..") {
+                return Err(self.scanner.error("Expected '..' between the bounds".to_string()));
+            }
+            let upper = self.bound()?;
+            return Ok(Condition::Within(contrast_type, lower, upper));
+        }
+
+        // Two character operators first, so `<=` isn't read as `<`
+        let comparison = [
+            ("<=", Comparison::LessOrEqual),
+            (">=", Comparison::GreaterOrEqual),
+            ("!=", Comparison::NotEqual),
+            ("==", Comparison::Equal),
+            ("<", Comparison::Less),
+            (">", Comparison::Greater),
+            ("=", Comparison::Equal),
+        ].into_iter().find(|(operator, _)| self.scanner.eat(operator)).map(|(_, comparison)| comparison);
+        let Some(comparison) = comparison else {
+            return Err(self.scanner.error(format!("Expected a comparison (<, <=, >, >=, =, != or in) after '{}'", name)));
+        };
+        Ok(Condition::Compare(contrast_type, comparison, self.bound()?))
+    }
+
+    fn bound(&mut self) -> Result<f32, ExpressionError> {
+        self.scanner.number().unwrap_or_else(|| Err(self.scanner.error("Expected a number".to_string())))
+    }
+}
+
+impl fmt::Debug for Predicate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.source)
+    }
+}
+
+/// Predicates are equal when they were written the same way.
+impl PartialEq for Predicate {
+    fn eq(&self, other: &Self) -> bool {
+        self.source == other.source
+    }
+}
+
+impl Eq for Predicate {}
+
+impl Serialize for Predicate {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.source)
+    }
+}
+
+impl<'de> Deserialize<'de> for Predicate {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let source = String::deserialize(deserializer)?;
+        Predicate::parse(&source).map_err(|err| serde::de::Error::custom(format!("The predicate \"{}\" is not valid: {}.", source, err)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgba;
+    use super::Predicate;
+
+    fn matches(source: &str, pixel: [u8; 3]) -> bool {
+        Predicate::parse(source).unwrap().matches(&Rgba([pixel[0], pixel[1], pixel[2], 255]))
+    }
+
+    #[test]
+    fn not_binds_tighter_than_and_which_binds_tighter_than_or() {
+        // Read as red or (green and blue)
+        assert!(matches("red > 0.5 or green > 0.5 and blue > 0.5", [255, 0, 0]));
+        assert!(!matches("(red > 0.5 or green > 0.5) and blue > 0.5", [255, 0, 0]));
+        // Read as (not red) and green
+        assert!(!matches("not red > 0.5 and green > 0.5", [0, 0, 0]));
+        assert!(matches("not (red > 0.5 and green > 0.5)", [0, 0, 0]));
+        assert!(matches("not not red > 0.5", [255, 0, 0]));
+    }
+
+    #[test]
+    fn keywords_can_be_written_in_any_case() {
+        assert!(matches("red > 0.5 OR green > 0.5", [0, 255, 0]));
+        assert!(matches("Not red > 0.5 AnD green > 0.5", [0, 255, 0]));
+        assert!(matches("green IN 0.9..1", [0, 255, 0]));
+    }
+
+    #[test]
+    fn in_checks_between_the_bounds_or_outside_them_when_they_wrap_around() {
+        assert!(matches("red in 0.4..0.6", [128, 0, 0]));
+        assert!(!matches("red in 0.4..0.6", [255, 0, 0]));
+        assert!(matches("red in .4..1", [255, 0, 0]));
+        // Hue from 0.9 round to 0.1 takes in the reds but not the greens
+        assert!(matches("hue in 0.9..0.1", [255, 0, 0]));
+        assert!(matches("hue in 0.9..0.1", [255, 0, 40]));
+        assert!(!matches("hue in 0.9..0.1", [0, 255, 0]));
+    }
+
+    #[test]
+    fn two_character_comparisons_are_not_read_as_one_character() {
+        assert!(matches("red <= 1", [255, 0, 0]));
+        assert!(!matches("red < 1", [255, 0, 0]));
+        assert!(matches("red>=1", [255, 0, 0]));
+        assert!(!matches("red != 1", [255, 0, 0]));
+        assert!(matches("red == 1 and red = 1", [255, 0, 0]));
+    }
+
+    #[test]
+    fn only_contrast_types_of_a_single_pixel_can_be_named() {
+        for name in ["edges", "expression", "predicate", "distance_to"] {
+            let err = Predicate::parse(&format!("value > 0.5 and {} > 0.5", name)).err().unwrap();
+            assert_eq!(err.column, 17, "{}", name);
+            assert!(err.message.starts_with(&format!("'{}' can't be used in a predicate", name)), "{}", err.message);
+        }
+        let err = Predicate::parse("brightness > 0.5").err().unwrap();
+        assert_eq!(err.column, 1);
+        assert!(err.message.starts_with("Unknown contrast type 'brightness'"));
+        assert_eq!(Predicate::parse("red 0.5").err().unwrap().column, 5);
+    }
+
+    #[test]
+    fn nesting_too_deeply_is_an_error() {
+        assert!(matches(&format!("{}red > 0.5", "not ".repeat(100)), [255, 0, 0]));
+        let err = Predicate::parse(&"not ".repeat(100_000)).err().unwrap();
+        assert_eq!((err.column, err.message.as_str()), (4*129, "Nested more than 128 levels deep"));
+        assert!(Predicate::parse(&format!("{}red > 0.5{}", "(".repeat(100_000), ")".repeat(100_000))).is_err());
+        assert!(Predicate::parse(&vec!["red > 0.5"; 100_000].join(" or ")).is_err());
+    }
+}
@@ -0,0 +1,35 @@
+/// SplitMix64, a small seeded generator. Random choices are derived from the seed and where they are made
+/// (e.g. the line and span), so results don't depend on the order threads get to them.
+#[derive(Debug, Clone)]
+pub struct SplitMix64 {
+    state: u64,
+}
+
+impl SplitMix64 {
+    pub fn new(seed: u64) -> Self {
+        SplitMix64 { state: seed }
+    }
+
+    /// A generator for one place in the image, e.g. a span identified by its line and start.
+    pub fn for_position(seed: u64, line: usize, start: usize) -> Self {
+        let mut mixer = SplitMix64::new(seed ^ (line as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15));
+        SplitMix64::new(mixer.next_u64() ^ start as u64)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    pub fn next_bool(&mut self) -> bool {
+        self.next_u64() >> 63 == 1
+    }
+
+    /// A uniform float in [0, 1).
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64/(1u64 << 53) as f64
+    }
+}
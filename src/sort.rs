@@ -0,0 +1,377 @@
+use std::ops::Range;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use image::Rgba;
+use rayon::prelude::*;
+use crate::random::SplitMix64;
+use crate::spans::find_spans;
+use crate::traversal::{traversal_for, Traversal};
+use crate::{colour_distance, luminance, rgb_to_hsv, rgb_to_lab, rgb_to_lch, rgb_to_oklch, KeyOrder, PixelSorter, SortBy, SortError, SortOrder};
+
+/// Spans shorter than this are sorted with a comparison sort, as clearing the counting sort's buckets would cost more than sorting them.
+const COUNTING_SORT_MIN_LEN: usize = 64;
+
+/// How many lines are sorted before they are written back, which bounds the memory used for sorted lines.
+const LINES_PER_BATCH: usize = 1024;
+
+/// Sorts every span of `true` values in the contrast map along the lines of the
+/// [`SortDirection`](crate::SortDirection). The contrast values decide where spans start in
+/// [`SpanMode::Hysteresis`](crate::SpanMode::Hysteresis) and where they are cut in
+/// [`SpanMode::Difference`](crate::SpanMode::Difference).
+/// The pixels, contrast map and contrast values must each have one entry per pixel of the `width` by `height` image.
+/// Spans are ordered along the sort direction by the sort order. Lines are sorted in parallel on the sorter's threads
+/// (all cores when 0); the result doesn't depend on the thread count.
+pub fn sort_pixels(sorter: &PixelSorter, pixels_vec: &mut [Rgba<u8>], contrast_map: &[bool], contrast_values: &[f32], width: usize, height: usize) -> Result<(), SortError> {
+    for (name, len) in [("pixels", pixels_vec.len()), ("contrast map", contrast_map.len()), ("contrast values", contrast_values.len())] {
+        if len != width*height {
+            return Err(SortError::WrongLength { name, len, expected: width*height });
+        }
+    }
+    if width == 0 || height == 0 {
+        return Ok(());
+    }
+
+    sorter.on_threads(|| sort_by_key(sorter, pixels_vec, contrast_map, contrast_values, width, height))?
+}
+
+/// Computes every pixel's key once, then sorts the spans by those keys. 8-bit keys are counting sorted.
+fn sort_by_key(sorter: &PixelSorter, pixels_vec: &mut [Rgba<u8>], contrast_map: &[bool], contrast_values: &[f32], width: usize, height: usize) -> Result<(), SortError> {
+    let traversal = traversal_for(sorter, width, height)?;
+    if !sorter.settings.tie_breakers.is_empty() {
+        let parts = multi_key_parts(sorter, pixels_vec);
+        let keys = parts.chunks(sorter.settings.tie_breakers.len() + 1).map(MultiKey).collect();
+        sort_along(sorter, traversal.as_ref(), pixels_vec, contrast_map, contrast_values, keys);
+        return Ok(());
+    }
+    match &sorter.settings.sort_by {
+        SortBy::Red => sort_along(sorter, traversal.as_ref(), pixels_vec, contrast_map, contrast_values, compute_keys(pixels_vec, |pixel| pixel[0])),
+        SortBy::Green => sort_along(sorter, traversal.as_ref(), pixels_vec, contrast_map, contrast_values, compute_keys(pixels_vec, |pixel| pixel[1])),
+        SortBy::Blue => sort_along(sorter, traversal.as_ref(), pixels_vec, contrast_map, contrast_values, compute_keys(pixels_vec, |pixel| pixel[2])),
+        // The value is the largest channel, so the byte sorts the same as the percentage
+        SortBy::Value => sort_along(sorter, traversal.as_ref(), pixels_vec, contrast_map, contrast_values, compute_keys(pixels_vec, |pixel| pixel[0].max(pixel[1]).max(pixel[2]))),
+        sort_by => sort_along(sorter, traversal.as_ref(), pixels_vec, contrast_map, contrast_values, compute_keys(pixels_vec, |pixel| float_key(sorter, sort_by, pixel))),
+    }
+    Ok(())
+}
+
+/// A pixel's key as a float, in the same order as its 8-bit key where it has one. Hues are measured from the hue origin.
+fn float_key(sorter: &PixelSorter, sort_by: &SortBy, pixel: &Rgba<u8>) -> f32 {
+    let from_origin = |hue: f32| (hue - sorter.settings.hue_origin).rem_euclid(360.0);
+    match sort_by {
+        SortBy::Red => pixel[0] as f32,
+        SortBy::Green => pixel[1] as f32,
+        SortBy::Blue => pixel[2] as f32,
+        SortBy::Hue => from_origin(rgb_to_hsv(pixel).0),
+        SortBy::Saturation => rgb_to_hsv(pixel).1,
+        SortBy::Value => pixel[0].max(pixel[1]).max(pixel[2]) as f32,
+        SortBy::Luminance => luminance(pixel),
+        // HSL lightness is the mean of the largest and smallest channel, so their sum sorts the same
+        SortBy::Lightness => (pixel[0].max(pixel[1]).max(pixel[2]) as u16 + pixel[0].min(pixel[1]).min(pixel[2]) as u16) as f32,
+        SortBy::LabLightness => rgb_to_lab(pixel).0,
+        SortBy::LchChroma => rgb_to_lch(pixel).1,
+        SortBy::LchHue => from_origin(rgb_to_lch(pixel).2),
+        SortBy::OklabLightness => rgb_to_oklch(pixel).0,
+        SortBy::OklabChroma => rgb_to_oklch(pixel).1,
+        SortBy::OklabHue => from_origin(rgb_to_oklch(pixel).2),
+        SortBy::Expression(expression) => expression.evaluate(pixel),
+        SortBy::DistanceTo { colour, space } => colour_distance(*space, pixel, colour),
+    }
+}
+
+fn compute_keys<K: Send>(pixels_vec: &[Rgba<u8>], key: impl Fn(&Rgba<u8>) -> K + Sync + Send) -> Vec<K> {
+    pixels_vec.par_iter().map(key).collect()
+}
+
+/// Keys a span can be sorted by.
+trait SortKey: Copy + Send + Sync {
+    /// Sorts the span and its keys into ascending (or descending) key order. Pixels with equal keys keep their order
+    /// when `stable` is set, whichever way the span is sorted.
+    fn sort_span(keys: &mut [Self], span: &mut [Rgba<u8>], stable: bool, descending: bool);
+
+    fn compare(&self, other: &Self) -> std::cmp::Ordering;
+}
+
+impl SortKey for u8 {
+    /// Stable counting sort, so it is always stable.
+    fn sort_span(keys: &mut [u8], span: &mut [Rgba<u8>], _stable: bool, descending: bool) {
+        if span.len() < COUNTING_SORT_MIN_LEN {
+            sort_pairs(keys, span, true, descending);
+            return;
+        }
+
+        let mut counts = [0usize; 256];
+        keys.iter().for_each(|key| counts[*key as usize] += 1);
+
+        // Turn the counts into where each bucket starts, from the top bucket down when descending
+        let mut next = [0usize; 256];
+        let mut start = 0;
+        for bucket in 0..256 {
+            let bucket = if descending {255 - bucket} else {bucket};
+            next[bucket] = start;
+            start += counts[bucket];
+        }
+
+        let mut sorted = vec![(0, Rgba([0, 0, 0, 0])); span.len()];
+        for (key, pixel) in keys.iter().zip(span.iter()) {
+            sorted[next[*key as usize]] = (*key, *pixel);
+            next[*key as usize] += 1;
+        }
+        keys.iter_mut().zip(span.iter_mut()).zip(sorted).for_each(|((key, pixel), pair)| (*key, *pixel) = pair);
+    }
+
+    fn compare(&self, other: &Self) -> std::cmp::Ordering {
+        self.cmp(other)
+    }
+}
+
+impl SortKey for f32 {
+    fn sort_span(keys: &mut [f32], span: &mut [Rgba<u8>], stable: bool, descending: bool) {
+        sort_pairs(keys, span, stable, descending);
+    }
+
+    fn compare(&self, other: &Self) -> std::cmp::Ordering {
+        self.total_cmp(other)
+    }
+}
+
+/// A pixel's parts of [`multi_key_parts`]: the sort key followed by each tie breaker.
+#[derive(Clone, Copy)]
+struct MultiKey<'a>(&'a [f32]);
+
+/// The sort key followed by each tie breaker for every pixel, one after another, with descending tie breakers negated
+/// so every part sorts ascending.
+fn multi_key_parts(sorter: &PixelSorter, pixels_vec: &[Rgba<u8>]) -> Vec<f32> {
+    let part_count = sorter.settings.tie_breakers.len() + 1;
+    let mut parts = vec![0.0; pixels_vec.len()*part_count];
+    parts.par_chunks_mut(part_count).zip(pixels_vec.par_iter()).for_each(|(parts, pixel)| {
+        parts[0] = float_key(sorter, &sorter.settings.sort_by, pixel);
+        for (part, tie_breaker) in parts[1..].iter_mut().zip(&sorter.settings.tie_breakers) {
+            let value = float_key(sorter, &tie_breaker.key, pixel);
+            *part = if tie_breaker.order == KeyOrder::Descending {-value} else {value};
+        }
+    });
+    parts
+}
+
+impl SortKey for MultiKey<'_> {
+    fn sort_span(keys: &mut [Self], span: &mut [Rgba<u8>], stable: bool, descending: bool) {
+        sort_pairs(keys, span, stable, descending);
+    }
+
+    fn compare(&self, other: &Self) -> std::cmp::Ordering {
+        self.0.iter().zip(other.0).map(|(a, b)| a.total_cmp(b)).find(|ordering| ordering.is_ne()).unwrap_or(std::cmp::Ordering::Equal)
+    }
+}
+
+/// Sorts the span by pairing every pixel with its key, for keys that can't be counting sorted. Descending spans are
+/// sorted with the comparison flipped rather than reversed afterwards, so equal keys still keep their order.
+fn sort_pairs<K: SortKey>(keys: &mut [K], span: &mut [Rgba<u8>], stable: bool, descending: bool) {
+    let compare = |a: &(K, Rgba<u8>), b: &(K, Rgba<u8>)| if descending {b.0.compare(&a.0)} else {a.0.compare(&b.0)};
+    let mut pairs: Vec<(K, Rgba<u8>)> = keys.iter().copied().zip(span.iter().copied()).collect();
+    if stable {
+        pairs.sort_by(compare);
+    } else {
+        pairs.sort_unstable_by(compare);
+    }
+    keys.iter_mut().zip(span.iter_mut()).zip(pairs).for_each(|((key, pixel), pair)| (*key, *pixel) = pair);
+}
+
+/// Sorts the spans along every line of the traversal. Lines are sorted in parallel a batch at a time and then written back.
+fn sort_along<K: SortKey>(sorter: &PixelSorter, traversal: &dyn Traversal, pixels_vec: &mut [Rgba<u8>], contrast_map: &[bool], contrast_values: &[f32], keys: Vec<K>) {
+    let line_count = traversal.line_count();
+    let lines_done = AtomicUsize::new(0);
+
+    for batch_start in (0..line_count).step_by(LINES_PER_BATCH) {
+        let batch = batch_start..line_count.min(batch_start + LINES_PER_BATCH);
+        let unsorted: &[Rgba<u8>] = pixels_vec;
+        let sorted_lines: Vec<Vec<Rgba<u8>>> = batch.clone().into_par_iter().map_init(|| (Vec::new(), Vec::new()), |(indices, spans), line| {
+            traversal.line(line, indices);
+            let mut line_pixels: Vec<Rgba<u8>> = indices.iter().map(|i| unsorted[*i]).collect();
+            let mut line_keys: Vec<K> = indices.iter().map(|i| keys[*i]).collect();
+            let line_map: Vec<bool> = indices.iter().map(|i| contrast_map[*i]).collect();
+            let line_values: Vec<f32> = indices.iter().map(|i| contrast_values[*i]).collect();
+            find_spans(sorter, line, &line_map, &line_values, spans);
+            sort_spans(sorter, line, &mut line_pixels, &mut line_keys, spans);
+
+            if sorter.settings.should_debug {
+                println!("{}%", (lines_done.fetch_add(1, Ordering::Relaxed) as f32/line_count as f32)*100.0);
+            }
+            line_pixels
+        }).collect();
+
+        // Put the lines back into the pixels vector
+        let mut indices = Vec::new();
+        for (line, line_pixels) in batch.zip(sorted_lines) {
+            traversal.line(line, &mut indices);
+            indices.iter().zip(line_pixels).for_each(|(i, pixel)| pixels_vec[*i] = pixel);
+        }
+    }
+}
+
+/// Sorts each of the line's spans into the sort order.
+fn sort_spans<K: SortKey>(sorter: &PixelSorter, line_index: usize, line: &mut [Rgba<u8>], line_keys: &mut [K], spans: &[Range<usize>]) {
+    for span in spans {
+        let descending = match sorter.settings.sort_order {
+            SortOrder::Ascending | SortOrder::Mirror => false,
+            SortOrder::Descending => true,
+            SortOrder::Alternating => line_index % 2 == 1,
+            SortOrder::Random => SplitMix64::for_position(sorter.settings.seed, line_index, span.start).next_bool(),
+        };
+        K::sort_span(&mut line_keys[span.clone()], &mut line[span.clone()], sorter.settings.stable_sort, descending);
+        if sorter.settings.sort_order == SortOrder::Mirror {
+            mirror_span(sorter, &line_keys[span.clone()], &mut line[span.clone()]);
+        }
+    }
+}
+
+/// Deals an ascending span out to both ends in turn, so the highest keys meet in the middle. With a stable sort, pixels
+/// with equal keys are dealt out together, so they stay side by side in their original order.
+fn mirror_span<K: SortKey>(sorter: &PixelSorter, keys: &[K], span: &mut [Rgba<u8>]) {
+    let ascending = span.to_vec();
+    let (mut front, mut back) = (0, span.len());
+    let (mut i, mut to_front) = (0, true);
+    while i < ascending.len() {
+        let mut j = i+1;
+        while sorter.settings.stable_sort && j < ascending.len() && keys[j].compare(&keys[i]).is_eq() {
+            j += 1;
+        }
+        if to_front {
+            span[front..front + j - i].copy_from_slice(&ascending[i..j]);
+            front += j - i;
+        } else {
+            back -= j - i;
+            span[back..back + j - i].copy_from_slice(&ascending[i..j]);
+        }
+        to_front = !to_front;
+        i = j;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgba, RgbaImage};
+    use crate::random::SplitMix64;
+    use crate::{sort_pixels, CentrePoint, KeyOrder, PixelSorter, SortBy, SortDirection, SortError, SortOrder, SpanSplit, TieBreaker};
+
+    fn noise_image(width: u32, height: u32) -> RgbaImage {
+        let mut random = SplitMix64::new(1);
+        RgbaImage::from_fn(width, height, |_, _| {
+            let bytes = random.next_u64().to_le_bytes();
+            Rgba([bytes[0], bytes[1], bytes[2], 255])
+        })
+    }
+
+    #[test]
+    fn thread_count_does_not_change_the_result() {
+        // 1100 columns are more than one batch of lines
+        let img = noise_image(1100, 23);
+        for (sort_direction, sort_by) in [(SortDirection::Down, SortBy::Red), (SortDirection::Angle(30.0), SortBy::Hue), (SortDirection::Hilbert, SortBy::Luminance)] {
+            let sorter = PixelSorter::new()
+                .sort_direction(sort_direction.clone())
+                .sort_order(SortOrder::Random)
+                .sort_by(sort_by.clone())
+                .contrast_bounds(0.2, 0.9);
+            let sequential = sorter.clone().threads(1).sort_rgba(&img).unwrap().image;
+            for threads in [0, 2, 7] {
+                let parallel = sorter.clone().threads(threads).sort_rgba(&img).unwrap().image;
+                assert!(parallel == sequential, "{:?} by {:?} on {} threads differs from one thread", sort_direction, sort_by, threads);
+            }
+        }
+    }
+
+    #[test]
+    fn stable_sort_keeps_ties_in_order_whichever_way_the_span_goes() {
+        // Four pixels go through the comparison sort and a hundred through the counting sort
+        for length in [4, 100] {
+            let img = RgbaImage::from_fn(length, 1, |x, _| Rgba([128, x as u8 + 1, 0, 255]));
+            let sorter = PixelSorter::new()
+                .sort_direction(SortDirection::Right)
+                .sort_by(SortBy::Red)
+                .stable_sort(true)
+                .contrast_bounds(0.0, 1.0);
+            for sort_order in [SortOrder::Ascending, SortOrder::Descending, SortOrder::Mirror] {
+                let sorted = sorter.clone().sort_order(sort_order).sort_rgba(&img).unwrap().image;
+                let greens: Vec<u8> = sorted.pixels().map(|pixel| pixel[1]).collect();
+                assert_eq!(greens, (1..=length as u8).collect::<Vec<u8>>(), "{:?} with {} pixels", sort_order, length);
+            }
+        }
+    }
+
+    #[test]
+    fn contrast_values_must_cover_every_pixel() {
+        let mut pixels = vec![Rgba([0, 0, 0, 255]); 16];
+        let result = sort_pixels(&PixelSorter::new(), &mut pixels, &[true; 16], &[], 4, 4);
+        assert_eq!(result, Err(SortError::WrongLength { name: "contrast values", len: 0, expected: 16 }));
+    }
+
+    #[test]
+    fn hues_are_sorted_from_the_hue_origin() {
+        // Hues of about 200, 5, 120 and 355 degrees
+        let hues = [Rgba([0, 170, 255, 255]), Rgba([255, 21, 0, 255]), Rgba([0, 255, 0, 255]), Rgba([255, 0, 21, 255])];
+        let img = RgbaImage::from_fn(4, 1, |x, _| hues[x as usize]);
+        let sorted = |hue_origin: f32| -> Vec<Rgba<u8>> {
+            let sorter = PixelSorter::new().sort_direction(SortDirection::Right).sort_by(SortBy::Hue).contrast_bounds(0.0, 1.0).hue_origin(hue_origin);
+            sorter.sort_rgba(&img).unwrap().image.pixels().copied().collect()
+        };
+        assert_eq!(sorted(0.0), [hues[1], hues[2], hues[0], hues[3]]);
+        // From 300 degrees round, 355 and 5 degrees are neighbours
+        assert_eq!(sorted(300.0), [hues[3], hues[1], hues[2], hues[0]]);
+        assert_eq!(sorted(-60.0), sorted(300.0));
+        assert_eq!(sorted(660.0), sorted(300.0));
+    }
+
+    #[test]
+    fn alternating_reverses_every_other_line() {
+        let img = RgbaImage::from_fn(4, 3, |x, _| Rgba([[2, 0, 3, 1][x as usize], 0, 0, 255]));
+        let sorter = PixelSorter::new().sort_direction(SortDirection::Right).sort_order(SortOrder::Alternating).contrast_bounds(0.0, 1.0);
+        let sorted = sorter.sort_rgba(&img).unwrap().image;
+        let rows: Vec<Vec<u8>> = sorted.rows().map(|row| row.map(|pixel| pixel[0]).collect()).collect();
+        assert_eq!(rows, [vec![0, 1, 2, 3], vec![3, 2, 1, 0], vec![0, 1, 2, 3]]);
+    }
+
+    #[test]
+    fn random_order_follows_the_seed() {
+        let img = noise_image(64, 16);
+        let sorter = PixelSorter::new().sort_direction(SortDirection::Right).sort_order(SortOrder::Random).contrast_bounds(0.0, 1.0);
+        let sorted = |seed: u64| sorter.clone().seed(seed).sort_rgba(&img).unwrap().image;
+        assert!(sorted(1) == sorted(1));
+        assert!(sorted(1) != sorted(2));
+
+        // Each line goes one way or the other, and both ways turn up
+        let rows: Vec<Vec<u8>> = sorted(1).rows().map(|row| row.map(|pixel| pixel[0]).collect()).collect();
+        let ascending = rows.iter().filter(|row| row.windows(2).all(|pair| pair[0] <= pair[1])).count();
+        let descending = rows.iter().filter(|row| row.windows(2).all(|pair| pair[0] >= pair[1])).count();
+        assert_eq!(ascending + descending, rows.len());
+        assert!(ascending > 0 && descending > 0);
+    }
+
+    #[test]
+    fn any_number_of_tie_breakers_can_be_used() {
+        let img = RgbaImage::from_fn(4, 1, |x, _| Rgba([9, [3, 1, 2, 0][x as usize], 5, 255]));
+        let tie = |key: SortBy, order: KeyOrder| TieBreaker { key, order };
+        // Only the fifth tie breaker tells the pixels apart
+        let tie_breakers = vec![
+            tie(SortBy::Red, KeyOrder::Ascending),
+            tie(SortBy::Blue, KeyOrder::Descending),
+            tie(SortBy::Value, KeyOrder::Ascending),
+            tie(SortBy::Red, KeyOrder::Descending),
+            tie(SortBy::Green, KeyOrder::Descending),
+        ];
+        let sorter = PixelSorter::new().sort_direction(SortDirection::Right).contrast_bounds(0.0, 1.0).tie_breakers(tie_breakers);
+        let greens: Vec<u8> = sorter.sort_rgba(&img).unwrap().image.pixels().map(|pixel| pixel[1]).collect();
+        assert_eq!(greens, [3, 2, 1, 0]);
+    }
+
+    #[test]
+    fn invalid_settings_are_an_error() {
+        let img = noise_image(8, 8);
+        let sorters = [
+            PixelSorter::new().sort_direction(SortDirection::Spiral { centre: CentrePoint::default(), spacing: 0.0 }),
+            PixelSorter::new().sort_direction(SortDirection::Angle(f32::NAN)),
+            PixelSorter::new().span_split(SpanSplit::Uniform { min: 0, max: 10 }),
+        ];
+        for sorter in sorters {
+            assert!(matches!(sorter.sort_rgba(&img), Err(SortError::InvalidSettings(_))), "{:?}", sorter.settings);
+            assert!(matches!(sorter.contrast_map(&img), Err(SortError::InvalidSettings(_))), "{:?}", sorter.settings);
+        }
+    }
+}
@@ -0,0 +1,137 @@
+use std::ops::Range;
+use crate::random::SplitMix64;
+use crate::{PixelSorter, SpanMode, SpanSplit};
+
+/// Mixed into the seed for span splitting, so the lengths don't follow the same numbers as the random sort order.
+const SPLIT_SEED: u64 = 0x0053_504C_4954;
+
+/// Finds the spans to sort along a line from its contrast map and contrast values, replacing what was in `spans`.
+pub(crate) fn find_spans(sorter: &PixelSorter, line_index: usize, line_map: &[bool], line_values: &[f32], spans: &mut Vec<Range<usize>>) {
+    spans.clear();
+    let mut push = |span: Range<usize>| add_span(sorter, line_index, span, spans);
+    let mut i = 0;
+    while i < line_map.len() {
+        if !line_map[i] {
+            i += 1;
+            continue;
+        }
+        let mut j = i+1;
+        while j < line_map.len() && line_map[j] {
+            j += 1;
+        }
+        match sorter.settings.span_mode {
+            SpanMode::Mask => push(i..j),
+            // Skip ahead to where the run first reaches the high threshold, if it ever does
+            SpanMode::Hysteresis { high } => if let Some(start) = (i..j).find(|k| line_values[*k] >= high) {
+                push(start..j);
+            },
+            // Cut the run between neighbours whose values jump by more than the delta
+            SpanMode::Difference { delta } => {
+                let mut start = i;
+                for k in i+1..j {
+                    if (line_values[k] - line_values[k - 1]).abs() > delta {
+                        push(start..k);
+                        start = k;
+                    }
+                }
+                push(start..j);
+            }
+        }
+        i = j;
+    }
+}
+
+/// Adds the span unless it is shorter than the minimum length, cut into pieces no longer than the maximum length (and
+/// of random lengths when spans are split).
+fn add_span(sorter: &PixelSorter, line_index: usize, span: Range<usize>, spans: &mut Vec<Range<usize>>) {
+    if span.len() < sorter.settings.min_span_length {
+        return;
+    }
+
+    let mut random = SplitMix64::for_position(sorter.settings.seed ^ SPLIT_SEED, line_index, span.start);
+    let mut start = span.start;
+    while start < span.end {
+        let length = match sorter.settings.span_split {
+            SpanSplit::Off => span.len(),
+            SpanSplit::Uniform { min, max } => min + (random.next_u64() % (max.saturating_sub(min) as u64 + 1)) as usize,
+            SpanSplit::Exponential { mean } => (-(mean as f64)*(1.0 - random.next_f64()).ln()).ceil() as usize,
+        };
+        let length = if sorter.settings.max_span_length > 0 {length.min(sorter.settings.max_span_length)} else {length};
+        let end = (start + length.max(1)).min(span.end);
+        spans.push(start..end);
+        start = end;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::Range;
+    use crate::{PixelSorter, SpanMode, SpanSplit};
+    use super::find_spans;
+
+    /// The spans found along one line of the contrast map, with the same contrast value everywhere.
+    fn spans(sorter: &PixelSorter, line_map: &[bool]) -> Vec<Range<usize>> {
+        let mut spans = Vec::new();
+        find_spans(sorter, 0, line_map, &vec![0.5; line_map.len()], &mut spans);
+        spans
+    }
+
+    /// The spans found along one line of the given contrast values, for a contrast map of the values above 0.0.
+    fn value_spans(sorter: &PixelSorter, line_values: &[f32]) -> Vec<Range<usize>> {
+        let line_map: Vec<bool> = line_values.iter().map(|value| *value > 0.0).collect();
+        let mut spans = Vec::new();
+        find_spans(sorter, 0, &line_map, line_values, &mut spans);
+        spans
+    }
+
+    #[test]
+    fn spans_shorter_than_the_minimum_are_left_unsorted() {
+        let line_map = [true, true, false, true, true, true, false, true];
+        assert_eq!(spans(&PixelSorter::new().span_lengths(3, 0), &line_map), vec![3..6]);
+        assert_eq!(spans(&PixelSorter::new().span_lengths(2, 0), &line_map), vec![0..2, 3..6]);
+    }
+
+    #[test]
+    fn spans_are_cut_at_the_maximum_length() {
+        assert_eq!(spans(&PixelSorter::new().span_lengths(0, 3), &[true; 10]), vec![0..3, 3..6, 6..9, 9..10]);
+        assert_eq!(spans(&PixelSorter::new().span_lengths(0, 5), &[true; 10]), vec![0..5, 5..10]);
+    }
+
+    #[test]
+    fn the_same_seed_cuts_spans_the_same_way() {
+        let sorter = PixelSorter::new().span_split(SpanSplit::Exponential { mean: 4.0 });
+        let cuts = spans(&sorter.clone().seed(7), &[true; 200]);
+        assert_eq!(spans(&sorter.clone().seed(7), &[true; 200]), cuts);
+        assert_ne!(spans(&sorter.seed(8), &[true; 200]), cuts);
+        assert!(cuts.len() > 1);
+    }
+
+    #[test]
+    fn uniform_splits_stay_between_their_lengths() {
+        for seed in 0..20 {
+            let cuts = spans(&PixelSorter::new().span_split(SpanSplit::Uniform { min: 3, max: 5 }).seed(seed), &[true; 100]);
+            assert_eq!(cuts.first().map(|span| span.start), Some(0));
+            assert_eq!(cuts.last().map(|span| span.end), Some(100));
+            assert!(cuts.windows(2).all(|pair| pair[0].end == pair[1].start));
+            // Only the last piece can be shorter, with what was left over
+            assert!(cuts[..cuts.len() - 1].iter().all(|span| (3..=5).contains(&span.len())), "seed {}: {:?}", seed, cuts);
+            assert!(cuts[cuts.len() - 1].len() <= 5);
+        }
+    }
+
+    #[test]
+    fn hysteresis_spans_start_at_the_high_threshold_and_run_to_the_end_of_the_run() {
+        let sorter = PixelSorter::new().span_mode(SpanMode::Hysteresis { high: 0.8 });
+        // The first run reaches 0.8 part way along and keeps going below it, the second never reaches it
+        let values = [0.2, 0.5, 0.8, 0.3, 0.9, 0.2, 0.0, 0.5, 0.7, 0.79, 0.0, 0.9];
+        assert_eq!(value_spans(&sorter, &values), vec![2..6, 11..12]);
+    }
+
+    #[test]
+    fn difference_spans_are_cut_where_neighbours_differ_by_more_than_the_delta() {
+        let sorter = PixelSorter::new().span_mode(SpanMode::Difference { delta: 0.25 });
+        // Steps of exactly 0.25 don't cut, a step of 0.5 does, and a pixel outside the map ends the run as usual
+        let values = [0.25, 0.5, 0.75, 0.25, 0.5, 0.0, 1.0, 0.75];
+        assert_eq!(value_spans(&sorter, &values), vec![0..3, 3..5, 6..8]);
+    }
+}
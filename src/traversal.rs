@@ -0,0 +1,398 @@
+use std::f64::consts::TAU;
+use rayon::prelude::*;
+use crate::flow::{self, FlowMap, NoiseField};
+use crate::{FlowSource, PixelSorter, SortDirection, SortError, WaveAxis};
+
+/// A way of walking the image as a set of lines that together visit every pixel exactly once.
+/// Spans are found and sorted along each line, with keys ascending in the order the line is walked.
+pub trait Traversal: Sync {
+    fn line_count(&self) -> usize;
+
+    /// Replaces `indices` with the row-major pixel indices of a line, in the order it is walked.
+    fn line(&self, line: usize, indices: &mut Vec<usize>);
+}
+
+/// Picks the traversal for the sort direction. Left and up walk backwards so the highest keys end up on that side.
+pub fn traversal_for(sorter: &PixelSorter, width: usize, height: usize) -> Result<Box<dyn Traversal>, SortError> {
+    Ok(match &sorter.settings.sort_direction {
+        SortDirection::Left => Box::new(Rows { width, height, reversed: true }),
+        SortDirection::Right | SortDirection::Horizontal => Box::new(Rows { width, height, reversed: false }),
+        SortDirection::Up => Box::new(Columns { width, height, reversed: true }),
+        SortDirection::Down | SortDirection::Vertical => Box::new(Columns { width, height, reversed: false }),
+        SortDirection::Angle(degrees) => Box::new(OffsetLines::angle(*degrees, width, height)),
+        SortDirection::Radial { centre } => Box::new(radial(centre.resolve(width, height), width, height)),
+        SortDirection::Concentric { centre } => Box::new(concentric(centre.resolve(width, height), width, height)),
+        SortDirection::Spiral { centre, spacing } => Box::new(spiral(centre.resolve(width, height), *spacing as f64, width, height)),
+        SortDirection::Flow(FlowSource::Map(path)) => {
+            let map = sorter.flow_map.as_ref().ok_or_else(|| SortError::MissingFlowMap(path.clone()))?;
+            if map.width() == 0 || map.height() == 0 {
+                return Err(SortError::EmptyFlowMap(path.clone()));
+            }
+            Box::new(flow::trace(&FlowMap { map, width, height }, width, height))
+        },
+        SortDirection::Flow(FlowSource::Noise { scale }) => Box::new(flow::trace(&NoiseField::new(sorter.settings.seed, *scale as f64), width, height)),
+        SortDirection::Wave { axis, amplitude, wavelength, phase } => Box::new(OffsetLines::wave(*axis, *amplitude, *wavelength, *phase, width, height)),
+        SortDirection::Serpentine => Box::new(serpentine(width, height)),
+        SortDirection::Hilbert => Box::new(hilbert(width, height)),
+        SortDirection::ZOrder => Box::new(z_order(width, height)),
+    })
+}
+
+/// Every row, walked left to right (right to left when reversed).
+pub struct Rows {
+    pub width: usize,
+    pub height: usize,
+    pub reversed: bool,
+}
+
+impl Traversal for Rows {
+    fn line_count(&self) -> usize {
+        self.height
+    }
+
+    fn line(&self, line: usize, indices: &mut Vec<usize>) {
+        indices.clear();
+        let row = line*self.width..(line+1)*self.width;
+        if self.reversed {
+            indices.extend(row.rev());
+        } else {
+            indices.extend(row);
+        }
+    }
+}
+
+/// Every column, walked top to bottom (bottom to top when reversed).
+pub struct Columns {
+    pub width: usize,
+    pub height: usize,
+    pub reversed: bool,
+}
+
+impl Traversal for Columns {
+    fn line_count(&self) -> usize {
+        self.width
+    }
+
+    fn line(&self, line: usize, indices: &mut Vec<usize>) {
+        indices.clear();
+        let column = (0..self.height).map(|y| y*self.width + line);
+        if self.reversed {
+            indices.extend(column.rev());
+        } else {
+            indices.extend(column);
+        }
+    }
+}
+
+/// Parallel digital lines, each the same run of offsets shifted across the image. Lines closer to horizontal than
+/// vertical have exactly one pixel per column (and the others one per row), so every pixel lies on exactly one line
+/// and is only ever moved along it.
+pub struct OffsetLines {
+    width: usize,
+    height: usize,
+    /// Whether lines step along x (one pixel per column) rather than along y.
+    steps_along_x: bool,
+    /// Walk the stepped axis from its end back to 0.
+    reversed: bool,
+    /// How far a line has drifted across at each step.
+    offsets: Vec<isize>,
+    /// The offsets paired with their steps and ordered by offset, so the steps where a line crosses the image can be
+    /// found without going through every step.
+    by_offset: Vec<(isize, usize)>,
+    /// Each run of lines that cross the image, as the first line's position and how many lines come before the run.
+    /// Lines are only skipped when the offsets jump by more than the image is across, as a steep wave can.
+    runs: Vec<(isize, usize)>,
+    line_count: usize,
+}
+
+impl OffsetLines {
+    fn new(steps_along_x: bool, reversed: bool, offsets: Vec<isize>, width: usize, height: usize) -> Self {
+        let across = if steps_along_x {height} else {width} as isize;
+        let mut by_offset: Vec<(isize, usize)> = offsets.iter().copied().zip(0..).collect();
+        by_offset.sort_unstable();
+
+        // A step with offset o puts lines -o to across - 1 - o in the image, so merge those ranges, lowest first
+        let mut runs = Vec::new();
+        let mut line_count = 0;
+        let mut run: Option<(isize, isize)> = None;
+        for (offset, _) in by_offset.iter().rev().filter(|_| across > 0) {
+            let (first, last) = (-offset, across - 1 - offset);
+            run = match run {
+                Some((start, end)) if first <= end + 1 => Some((start, end.max(last))),
+                _ => {
+                    if let Some((start, end)) = run {
+                        runs.push((start, line_count));
+                        line_count += (end - start + 1) as usize;
+                    }
+                    Some((first, last))
+                },
+            };
+        }
+        if let Some((start, end)) = run {
+            runs.push((start, line_count));
+            line_count += (end - start + 1) as usize;
+        }
+
+        OffsetLines { width, height, steps_along_x, reversed, offsets, by_offset, runs, line_count }
+    }
+
+    /// Straight lines at an angle in degrees, counterclockwise from right.
+    pub fn angle(degrees: f32, width: usize, height: usize) -> Self {
+        let radians = (degrees as f64).to_radians();
+        // Image y points down, so going up is negative y
+        let (dx, dy) = (radians.cos(), -radians.sin());
+
+        let steps_along_x = dx.abs() >= dy.abs();
+        let (steps, slope, reversed) = if steps_along_x {
+            (width, dy/dx, dx < 0.0)
+        } else {
+            (height, dx/dy, dy < 0.0)
+        };
+
+        let offsets = (0..steps).map(|step| (slope*step as f64).round() as isize).collect();
+        OffsetLines::new(steps_along_x, reversed, offsets, width, height)
+    }
+
+    /// Sine waves walked left to right (or top to bottom when vertical), with the amplitude and wavelength in pixels
+    /// and the phase in degrees.
+    pub fn wave(axis: WaveAxis, amplitude: f32, wavelength: f32, phase: f32, width: usize, height: usize) -> Self {
+        let steps_along_x = axis == WaveAxis::Horizontal;
+        let steps = if steps_along_x {width} else {height};
+        // Clamped far past any image's size, so adding offsets together can't overflow
+        let (amplitude, wavelength, phase) = ((amplitude as f64).clamp(-1e12, 1e12), wavelength as f64, (phase as f64).to_radians());
+
+        // Image y points down, so a horizontal wave is flipped to rise first like it would on a graph
+        let sign = if steps_along_x {-1.0} else {1.0};
+        let offsets = (0..steps).map(|step| (sign*amplitude*(TAU*step as f64/wavelength + phase).sin()).round() as isize).collect();
+        OffsetLines::new(steps_along_x, false, offsets, width, height)
+    }
+}
+
+impl Traversal for OffsetLines {
+    fn line_count(&self) -> usize {
+        self.line_count
+    }
+
+    fn line(&self, line: usize, indices: &mut Vec<usize>) {
+        indices.clear();
+        let (first_line, lines_before) = self.runs[self.runs.partition_point(|(_, lines_before)| *lines_before <= line) - 1];
+        let line = first_line + (line - lines_before) as isize;
+        let across = if self.steps_along_x {self.height} else {self.width} as isize;
+
+        // The steps whose offsets put this line in the image, in the order they are walked
+        let start = self.by_offset.partition_point(|(offset, _)| line + offset < 0);
+        let end = self.by_offset.partition_point(|(offset, _)| line + offset < across);
+        indices.extend(self.by_offset[start..end].iter().map(|(_, step)| *step));
+        indices.sort_unstable();
+        if self.reversed {
+            indices.reverse();
+        }
+        for index in indices.iter_mut() {
+            let step = *index;
+            let position = (line + self.offsets[step]) as usize;
+            *index = if self.steps_along_x {position*self.width + step} else {step*self.width + position};
+        }
+    }
+}
+
+/// Lines worked out ahead of time, for traversals where a pixel's line depends on the whole layout.
+pub struct GroupedLines {
+    /// Where each line starts in `indices`, plus the end of the last line.
+    starts: Vec<usize>,
+    indices: Vec<usize>,
+}
+
+impl GroupedLines {
+    pub fn new(starts: Vec<usize>, indices: Vec<usize>) -> Self {
+        GroupedLines { starts, indices }
+    }
+
+    /// Puts every pixel on the line `place` gives it, walking each line in order of the position `place` gives within it.
+    pub fn group(line_count: usize, width: usize, height: usize, place: impl Fn(usize, usize) -> (usize, f64) + Sync + Send) -> Self {
+        let places: Vec<(usize, f64)> = (0..width*height).into_par_iter().map(|i| place(i % width, i / width)).collect();
+
+        // Count the pixels on each line to find where every line starts
+        let mut starts = vec![0; line_count + 1];
+        places.iter().for_each(|(line, _)| starts[line + 1] += 1);
+        for line in 0..line_count {
+            starts[line + 1] += starts[line];
+        }
+
+        let mut next = starts.clone();
+        let mut indices = vec![0; places.len()];
+        for (i, (line, _)) in places.iter().enumerate() {
+            indices[next[*line]] = i;
+            next[*line] += 1;
+        }
+
+        // Order each line by position
+        let mut lines: Vec<&mut [usize]> = Vec::with_capacity(line_count);
+        let mut rest = indices.as_mut_slice();
+        for line in 0..line_count {
+            let (line_indices, after) = rest.split_at_mut(starts[line + 1] - starts[line]);
+            lines.push(line_indices);
+            rest = after;
+        }
+        lines.into_par_iter().for_each(|line_indices| line_indices.sort_unstable_by(|a, b| places[*a].1.total_cmp(&places[*b].1).then(a.cmp(b))));
+
+        GroupedLines { starts, indices }
+    }
+}
+
+impl Traversal for GroupedLines {
+    fn line_count(&self) -> usize {
+        self.starts.len() - 1
+    }
+
+    fn line(&self, line: usize, indices: &mut Vec<usize>) {
+        indices.clear();
+        indices.extend_from_slice(&self.indices[self.starts[line]..self.starts[line + 1]]);
+    }
+}
+
+/// The distance and counterclockwise angle (0 to tau, 0 is right) of a pixel from the centre.
+fn polar((centre_x, centre_y): (f64, f64), x: usize, y: usize) -> (f64, f64) {
+    let (dx, dy) = (x as f64 - centre_x, centre_y - y as f64);
+    (dx.hypot(dy), dy.atan2(dx).rem_euclid(TAU))
+}
+
+/// The furthest any pixel is from the centre.
+fn max_distance(centre: (f64, f64), width: usize, height: usize) -> f64 {
+    [(0, 0), (width - 1, 0), (0, height - 1), (width - 1, height - 1)].iter().map(|(x, y)| polar(centre, *x, *y).0).fold(0.0, f64::max)
+}
+
+/// Rays from the centre, each walked outwards. There are enough rays for neighbouring rays to be a pixel apart at the edge.
+pub fn radial(centre: (f64, f64), width: usize, height: usize) -> GroupedLines {
+    let ray_count = ((TAU*max_distance(centre, width, height)).ceil() as usize).max(1);
+    GroupedLines::group(ray_count, width, height, |x, y| {
+        let (distance, angle) = polar(centre, x, y);
+        ((angle/TAU*ray_count as f64).round() as usize % ray_count, distance)
+    })
+}
+
+/// One pixel wide rings around the centre, each walked counterclockwise from the right.
+pub fn concentric(centre: (f64, f64), width: usize, height: usize) -> GroupedLines {
+    let ring_count = max_distance(centre, width, height).round() as usize + 1;
+    GroupedLines::group(ring_count, width, height, |x, y| {
+        let (distance, angle) = polar(centre, x, y);
+        (distance.round() as usize, angle)
+    })
+}
+
+/// A single Archimedean spiral walked outwards from the centre, with its turns `spacing` pixels apart.
+/// Each pixel is placed on the turn closest to it.
+pub fn spiral(centre: (f64, f64), spacing: f64, width: usize, height: usize) -> GroupedLines {
+    GroupedLines::group(1, width, height, |x, y| {
+        let (distance, angle) = polar(centre, x, y);
+        let turn = ((distance/spacing - angle/TAU).round()).max(0.0);
+        (0, turn*TAU + angle)
+    })
+}
+
+/// One path along every row in turn, right to left on odd rows, so the path never jumps.
+pub fn serpentine(width: usize, height: usize) -> GroupedLines {
+    GroupedLines::group(1, width, height, |x, y| {
+        let x = if y % 2 == 1 {width - 1 - x} else {x};
+        (0, (y*width + x) as f64)
+    })
+}
+
+/// The side of the smallest power of two square covering the image, which the curves below are laid over.
+/// Pixels of the square outside the image are skipped, so on other sizes the curve jumps where it leaves the image.
+fn curve_side(width: usize, height: usize) -> usize {
+    width.max(height).next_power_of_two()
+}
+
+/// One Hilbert curve through the whole image.
+pub fn hilbert(width: usize, height: usize) -> GroupedLines {
+    let side = curve_side(width, height);
+    GroupedLines::group(1, width, height, |x, y| (0, hilbert_distance(side, x, y) as f64))
+}
+
+/// How far along a Hilbert curve filling a `side` by `side` square the point is.
+fn hilbert_distance(side: usize, mut x: usize, mut y: usize) -> usize {
+    let mut distance = 0;
+    let mut quadrant_side = side/2;
+    while quadrant_side > 0 {
+        let right = (x & quadrant_side > 0) as usize;
+        let lower = (y & quadrant_side > 0) as usize;
+        distance += quadrant_side*quadrant_side*((3*right) ^ lower);
+        // Rotate the quadrant so the curve inside it starts and ends in the right corners
+        if lower == 0 {
+            if right == 1 {
+                x = side - 1 - x;
+                y = side - 1 - y;
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+        quadrant_side /= 2;
+    }
+    distance
+}
+
+/// One Morton (Z-order) curve through the whole image.
+pub fn z_order(width: usize, height: usize) -> GroupedLines {
+    GroupedLines::group(1, width, height, |x, y| (0, (spread_bits(x) | spread_bits(y) << 1) as f64))
+}
+
+/// Moves each bit of `value` to twice its position, leaving gaps to interleave another value's bits into.
+fn spread_bits(value: usize) -> usize {
+    (0..usize::BITS as usize/2).fold(0, |spread, bit| spread | (value >> bit & 1) << (2*bit))
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgba, RgbaImage};
+    use crate::{CentrePoint, FlowSource, PixelSorter, SortDirection, SortError, WaveAxis};
+    use super::traversal_for;
+
+    const SIZES: [(usize, usize); 7] = [(1, 1), (1, 17), (17, 1), (13, 7), (7, 13), (33, 20), (64, 64)];
+
+    fn directions() -> Vec<SortDirection> {
+        let off_image = CentrePoint::Pixels(-20.0, 150.0);
+        vec![
+            SortDirection::Left, SortDirection::Right, SortDirection::Up, SortDirection::Down,
+            SortDirection::Angle(0.0), SortDirection::Angle(17.5), SortDirection::Angle(45.0), SortDirection::Angle(90.0),
+            SortDirection::Angle(135.0), SortDirection::Angle(270.0), SortDirection::Angle(-30.0), SortDirection::Angle(1000.0),
+            SortDirection::Radial { centre: CentrePoint::default() },
+            SortDirection::Radial { centre: off_image },
+            SortDirection::Concentric { centre: CentrePoint::Fraction(0.0, 1.0) },
+            SortDirection::Concentric { centre: off_image },
+            SortDirection::Spiral { centre: CentrePoint::default(), spacing: 1.0 },
+            SortDirection::Spiral { centre: off_image, spacing: 3.5 },
+            SortDirection::Flow(FlowSource::Map("flow.png".to_string())),
+            SortDirection::Flow(FlowSource::Noise { scale: 5.0 }),
+            SortDirection::Wave { axis: WaveAxis::Horizontal, amplitude: 10.0, wavelength: 7.0, phase: 30.0 },
+            SortDirection::Wave { axis: WaveAxis::Vertical, amplitude: 3.0, wavelength: 100.0, phase: 0.0 },
+            // Steep enough that most lines between the highest and lowest offsets miss the image
+            SortDirection::Wave { axis: WaveAxis::Horizontal, amplitude: 1e9, wavelength: 37.0, phase: 0.0 },
+            SortDirection::Wave { axis: WaveAxis::Vertical, amplitude: -1e30, wavelength: 5.0, phase: 10.0 },
+            SortDirection::Serpentine, SortDirection::Hilbert, SortDirection::ZOrder,
+        ]
+    }
+
+    #[test]
+    fn every_pixel_is_visited_exactly_once() {
+        // Swirls round so flow paths run into each other
+        let flow_map = RgbaImage::from_fn(9, 9, |x, y| Rgba([(128 + 14*(y as i32 - 4)) as u8, (128 + 14*(x as i32 - 4)) as u8, 0, 255]));
+        for sort_direction in directions() {
+            let sorter = PixelSorter::new().sort_direction(sort_direction.clone()).flow_map(flow_map.clone());
+            for (width, height) in SIZES {
+                let traversal = traversal_for(&sorter, width, height).unwrap();
+                let mut visits = vec![0; width*height];
+                let mut indices = Vec::new();
+                for line in 0..traversal.line_count() {
+                    traversal.line(line, &mut indices);
+                    indices.iter().for_each(|i| visits[*i] += 1);
+                }
+                assert!(visits.iter().all(|count| *count == 1), "{:?} on {}x{} visits {:?}", sort_direction, width, height, visits);
+            }
+        }
+    }
+
+    #[test]
+    fn empty_flow_map_is_an_error() {
+        let sorter = PixelSorter::new().sort_direction(SortDirection::Flow(FlowSource::Map("flow.png".to_string()))).flow_map(RgbaImage::new(0, 0));
+        assert!(matches!(traversal_for(&sorter, 8, 8), Err(SortError::EmptyFlowMap(_))));
+    }
+}